@@ -1,10 +1,17 @@
 use serde::Serialize;
 use crate::miner::Handle as MinerHandle;
+use crate::miner::getwork::Getwork;
+use crate::miner::pool::{Pool, Share};
+use crate::crypto::hash::H160;
 use crate::network::server::Handle as NetworkServerHandle;
 use crate::network::message::Message;
+use crate::mempool::Mempool;
+use crate::transaction::SignedTransaction;
 
+use crossbeam::channel::Sender;
 use log::info;
 use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
 use std::thread;
 use tiny_http::Header;
 use tiny_http::Response;
@@ -14,7 +21,11 @@ use url::Url;
 pub struct Server {
     handle: HTTPServer,
     miner: MinerHandle,
+    getwork: Getwork,
+    pool: Pool,
     network: NetworkServerHandle,
+    mempool: Arc<Mutex<Mempool>>,
+    shutdown: Sender<()>,
 }
 
 #[derive(Serialize)]
@@ -23,6 +34,31 @@ struct ApiResponse {
     message: String,
 }
 
+#[derive(Serialize)]
+struct TransactionInfo {
+    txid: String,
+    wtxid: String,
+    sender: String,
+    recipient: String,
+    value: u32,
+    fee: u32,
+    account_nonce: u8,
+}
+
+impl From<&SignedTransaction> for TransactionInfo {
+    fn from(t: &SignedTransaction) -> Self {
+        TransactionInfo {
+            txid: t.txid().to_string(),
+            wtxid: t.wtxid().to_string(),
+            sender: t.sender().to_string(),
+            recipient: t.tx.recipient_address.to_string(),
+            value: t.tx.value,
+            fee: t.tx.fee,
+            account_nonce: t.tx.account_nonce,
+        }
+    }
+}
+
 macro_rules! respond_result {
     ( $req:expr, $success:expr, $message:expr ) => {{
         let content_type = "Content-Type: application/json".parse::<Header>().unwrap();
@@ -36,22 +72,43 @@ macro_rules! respond_result {
     }};
 }
 
+macro_rules! respond_json {
+    ( $req:expr, $payload:expr ) => {{
+        let content_type = "Content-Type: application/json".parse::<Header>().unwrap();
+        let resp = Response::from_string(serde_json::to_string_pretty(&$payload).unwrap())
+            .with_header(content_type);
+        $req.respond(resp).unwrap();
+    }};
+}
+
 impl Server {
     pub fn start(
         addr: std::net::SocketAddr,
         miner: &MinerHandle,
+        getwork: &Getwork,
+        pool: &Pool,
         network: &NetworkServerHandle,
+        mempool: &Arc<Mutex<Mempool>>,
+        shutdown: &Sender<()>,
     ) {
         let handle = HTTPServer::http(&addr).unwrap();
         let server = Self {
             handle,
             miner: miner.clone(),
+            getwork: getwork.clone(),
+            pool: pool.clone(),
             network: network.clone(),
+            mempool: Arc::clone(mempool),
+            shutdown: shutdown.clone(),
         };
         thread::spawn(move || {
             for req in server.handle.incoming_requests() {
                 let miner = server.miner.clone();
+                let getwork = server.getwork.clone();
+                let pool = server.pool.clone();
                 let network = server.network.clone();
+                let mempool = Arc::clone(&server.mempool);
+                let shutdown = server.shutdown.clone();
                 thread::spawn(move || {
                     // a valid url requires a base
                     let base_url = Url::parse(&format!("http://{}/", &addr)).unwrap();
@@ -87,10 +144,153 @@ impl Server {
                             miner.start(lambda);
                             respond_result!(req, true, "ok");
                         }
+                        "/miner/generate" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let (count, address) = match (params.get("count"), params.get("address")) {
+                                (Some(count), Some(address)) => (count, address),
+                                _ => {
+                                    respond_result!(req, false, "missing count or address");
+                                    return;
+                                }
+                            };
+                            let count = match count.parse::<usize>() {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(
+                                        req,
+                                        false,
+                                        format!("error parsing count: {}", e)
+                                    );
+                                    return;
+                                }
+                            };
+                            let address = match address.parse::<H160>() {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(
+                                        req,
+                                        false,
+                                        format!("error parsing address: {}", e)
+                                    );
+                                    return;
+                                }
+                            };
+                            let hashes: Vec<String> = miner
+                                .mine_blocks(count, address)
+                                .iter()
+                                .map(|hash| hash.to_string())
+                                .collect();
+                            respond_json!(req, hashes);
+                        }
+                        "/miner/stats" => {
+                            respond_json!(req, miner.stats());
+                        }
+                        "/miner/pause" => {
+                            miner.pause();
+                            respond_result!(req, true, "ok");
+                        }
+                        "/miner/resume" => {
+                            miner.resume();
+                            respond_result!(req, true, "ok");
+                        }
+                        "/getwork" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            // the reward goes to the node's miner address unless asked otherwise
+                            match params.get("address").map(|address| address.parse::<H160>()) {
+                                None => respond_json!(req, getwork.work()),
+                                Some(Ok(address)) => respond_json!(req, getwork.work_to(address)),
+                                Some(Err(e)) => {
+                                    respond_result!(
+                                        req,
+                                        false,
+                                        format!("error parsing address: {}", e)
+                                    );
+                                }
+                            }
+                        }
+                        "/getwork/submit" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let (id, nonce) = match (params.get("id"), params.get("nonce")) {
+                                (Some(id), Some(nonce)) => (id, nonce),
+                                _ => {
+                                    respond_result!(req, false, "missing id or nonce");
+                                    return;
+                                }
+                            };
+                            let (id, nonce) = match (id.parse::<u32>(), nonce.parse::<u32>()) {
+                                (Ok(id), Ok(nonce)) => (id, nonce),
+                                _ => {
+                                    respond_result!(req, false, "error parsing id or nonce");
+                                    return;
+                                }
+                            };
+                            match getwork.submit(id, nonce) {
+                                Ok(hash) => respond_result!(req, true, hash),
+                                Err(e) => respond_result!(req, false, e),
+                            }
+                        }
+                        "/pool/work" => {
+                            respond_json!(req, pool.work());
+                        }
+                        "/pool/submit" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let (worker, id, nonce) =
+                                match (params.get("worker"), params.get("id"), params.get("nonce")) {
+                                    (Some(worker), Some(id), Some(nonce)) => (worker, id, nonce),
+                                    _ => {
+                                        respond_result!(req, false, "missing worker, id or nonce");
+                                        return;
+                                    }
+                                };
+                            let worker = match worker.parse::<H160>() {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(
+                                        req,
+                                        false,
+                                        format!("error parsing worker: {}", e)
+                                    );
+                                    return;
+                                }
+                            };
+                            let (id, nonce) = match (id.parse::<u32>(), nonce.parse::<u32>()) {
+                                (Ok(id), Ok(nonce)) => (id, nonce),
+                                _ => {
+                                    respond_result!(req, false, "error parsing id or nonce");
+                                    return;
+                                }
+                            };
+                            match pool.submit(worker, id, nonce) {
+                                Ok(Share::Accepted) => respond_result!(req, true, "share accepted"),
+                                Ok(Share::Block(hash)) => respond_result!(req, true, hash),
+                                Err(e) => respond_result!(req, false, e),
+                            }
+                        }
+                        "/pool/stats" => {
+                            respond_json!(req, pool.stats());
+                        }
+                        "/node/shutdown" => {
+                            respond_result!(req, true, "ok");
+                            shutdown.send(()).unwrap();
+                        }
+                        "/mempool/transactions" => {
+                            let mp = mempool.lock().unwrap();
+                            let txs: Vec<TransactionInfo> =
+                                mp.transactions().iter().map(TransactionInfo::from).collect();
+                            drop(mp);
+                            respond_json!(req, txs);
+                        }
                         "/network/ping" => {
                             network.broadcast(Message::Ping(String::from("Test ping")));
                             respond_result!(req, true, "ok");
                         }
+                        "/network/peers" => {
+                            respond_json!(req, network.peers());
+                        }
                         _ => {
                             let content_type =
                                 "Content-Type: application/json".parse::<Header>().unwrap();
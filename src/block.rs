@@ -1,5 +1,5 @@
 use serde::{Serialize, Deserialize};
-use crate::crypto::hash::{H256, Hashable};
+use crate::crypto::hash::{H160, H256, Hashable};
 use crate::blockchain::Blockchain;
 use crate::crypto::merkle::*;
 use crate::transaction::*;
@@ -20,6 +20,10 @@ pub struct Header {
     pub difficulty: H256,
     pub timestamp: u128,
     pub merkle_root: H256,
+    // widens the search space once every value of `nonce` has been tried
+    pub extra_nonce: u32,
+    // credited with the block reward
+    pub coinbase: H160,
 }
 
 
@@ -55,6 +59,11 @@ impl Block{
             data,
         }
     }
+
+    /// Serialized size in bytes
+    pub fn size(&self) -> usize {
+        bincode::serialized_size(self).unwrap() as usize
+    }
 }
 
 impl Header{
@@ -65,6 +74,8 @@ impl Header{
             difficulty,
             timestamp,
             merkle_root,
+            extra_nonce: 0,
+            coinbase: H160::default(),
         }
     }
 }
@@ -1,16 +1,34 @@
 use crate::block::{Block, Header};
 use crate::crypto::hash::{H256,H160, Hashable};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use ring::signature::{self,Ed25519KeyPair, Signature, KeyPair, VerificationAlgorithm, EdDSAParameters};
 use crate::crypto::key_pair;
-use super::*;
+use crate::transaction::conversion;
+use crate::params::ChainParams;
 
+/// Reasons a header cannot join the header tree
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum HeaderError {
+    UnknownParent(H256),
+    InvalidProofOfWork(H256),
+}
+
+/// Account nonce and balance of every address
+pub type State = HashMap<H160, (u8, u32)>;
 
 pub struct Blockchain{
     // use hashmap to save the blocks and heights
     pub blocks: HashMap<H256, Block>,
     pub heights: HashMap<H256, u32>,
-    pub state: HashMap<H160, (u8, u32)>,
+    pub params: ChainParams,
+    // headers with valid proof of work, including those whose block has not arrived yet
+    pub headers: HashMap<H256, (Header, u32)>,
+    // our own blocks not published yet, left out of everything peers can see
+    withheld: HashSet<H256>,
+    // last block of the longest public chain, the first one seen wins ties
+    tip: H256,
+    // the state after each block, so a side branch never touches the state of the tip
+    states: HashMap<H256, State>,
     // pub account: Vec<Ed25519KeyPair>,
     // pub tip: H256,
 }
@@ -19,46 +37,54 @@ pub struct Blockchain{
 impl Blockchain {
     /// Create a new blockchain, only containing the genesis block
     pub fn new() -> Self {
+        Self::with_params(ChainParams::main())
+    }
+
+    /// Create a new blockchain of the network described by `params`
+    pub fn with_params(params: ChainParams) -> Self {
         // initiate the header and data for the genesis block
         let data = vec![];
         let parent = [0;32].into();
         let nonce = 1;
-        let difficulty = [0,1,14,4,3,1,1,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0].into();
+        let difficulty = params.difficulty;
         // let difficulty = (hex!("6b787718210e0b3b608814e04e61fde06d0df794319a12162f287412df3ec920")).into();
         let timestamp = 2;
         let merkle_root = [0;32].into();
-        let genesis_header = Header{parent, nonce, difficulty, timestamp, merkle_root};
+        let genesis_header = Header::new(parent, nonce, difficulty, timestamp, merkle_root);
         let header = genesis_header;
+        let mut headers = HashMap::new();
+        headers.insert(header.hash(), (header.clone(), 0));
         let genesis = Block{header, data};
         let genesis_hash = genesis.hash();
         // initiate
         let mut blocks = HashMap::new();
         let mut heights = HashMap::new();
         let mut state = HashMap::new();
-        // let mut account = Vec::new();
-        for user in 1..5{
-            let u = key_pair::random();
+        // initial coin offering, every node derives the same accounts
+        for user in 0..params.ico_accounts{
+            let u = key_pair::ico(user);
             let public_key = u.public_key();
             let account_address = conversion(public_key).into();
-            let balance = 20;
-            let account_nonce = user;
-            // account.push(u);
-            state.insert(account_address, (account_nonce, balance));
+            state.insert(account_address, (0, params.ico_balance));
         }
         // for the genesis block, the height must be 0
         blocks.insert(genesis_hash, genesis);
         heights.insert(genesis_hash, 0);
-        // initial coin offering
+        let mut states = HashMap::new();
+        states.insert(genesis_hash, state);
         Blockchain{
             blocks,
             heights,
-            state,
-            // account,
+            params,
+            headers,
+            withheld: HashSet::new(),
+            tip: genesis_hash,
+            states,
         }
     }
 
-    /// Insert a block into blockchain
-    pub fn insert(&mut self, block: &Block) {
+    /// Insert a block into blockchain, with the state after executing it on its parent's state
+    pub fn insert(&mut self, block: &Block, state: State) {
         // insert the new block into blockchain
         let now_hash = block.hash();
         // use the parent's hash to find parent's height
@@ -68,20 +94,219 @@ impl Blockchain {
         // deep copy the block
         let now_block = block.clone();
         self.heights.insert(now_hash.clone(), now_height);
+        self.headers.insert(now_hash, (block.header.clone(), now_height));
         self.blocks.insert(now_hash, now_block);
+        self.states.insert(now_hash, state);
+        if now_height > self.heights[&self.tip] {
+            self.tip = now_hash;
+        }
+    }
+
+    /// Keep a block we mined private: it stays off the tip, and is not served to peers until
+    /// `publish` is called.
+    pub fn withhold(&mut self, hash: H256) {
+        self.withheld.insert(hash);
+        if self.tip == hash {
+            self.tip = self
+                .heights
+                .iter()
+                .filter(|(hash, _)| !self.withheld.contains(hash))
+                .max_by_key(|(_, height)| **height)
+                .map(|(hash, _)| *hash)
+                .expect("failed");
+        }
+    }
+
+    /// Make a withheld block public. Its parent has to be public already.
+    pub fn publish(&mut self, hash: H256) {
+        if self.withheld.remove(&hash) && self.heights[&hash] > self.heights[&self.tip] {
+            self.tip = hash;
+        }
+    }
+
+    pub fn is_withheld(&self, hash: &H256) -> bool {
+        self.withheld.contains(hash)
+    }
+
+    /// A block peers may get from us
+    pub fn public_block(&self, hash: &H256) -> Option<&Block> {
+        if self.withheld.contains(hash) {
+            return None;
+        }
+        self.blocks.get(hash)
+    }
+
+    /// Insert a header whose block may arrive later, returns whether it was new
+    pub fn insert_header(&mut self, header: &Header) -> Result<bool, HeaderError> {
+        let hash = header.hash();
+        if self.headers.contains_key(&hash) {
+            return Ok(false);
+        }
+        self.check_proof_of_work(header)?;
+        let parent_height = match self.headers.get(&header.parent) {
+            Some((parent, height)) => {
+                // every block has to meet the target of its parent
+                if header.difficulty != parent.difficulty {
+                    return Err(HeaderError::InvalidProofOfWork(hash));
+                }
+                *height
+            }
+            None => return Err(HeaderError::UnknownParent(header.parent)),
+        };
+        self.headers.insert(hash, (header.clone(), parent_height + 1));
+        Ok(true)
+    }
+
+    /// Forget a header whose block turned out invalid, with every header built on it, so its
+    /// chain is not downloaded again
+    pub fn discard_header(&mut self, hash: H256) {
+        let mut discarded = HashSet::new();
+        discarded.insert(hash);
+        // headers come in any order, repeat until no header is left hanging
+        loop {
+            let orphaned: Vec<H256> = self
+                .headers
+                .iter()
+                .filter(|(hash, (header, _))| {
+                    !discarded.contains(*hash) && discarded.contains(&header.parent)
+                })
+                .map(|(hash, _)| *hash)
+                .collect();
+            if orphaned.is_empty() {
+                break;
+            }
+            discarded.extend(orphaned);
+        }
+        for hash in &discarded {
+            if !self.blocks.contains_key(hash) {
+                self.headers.remove(hash);
+            }
+        }
+    }
+
+    /// Check the proof of work of a header whose parent may not be known yet. The difficulty
+    /// never changes, so it has to be the one of the network.
+    pub fn check_proof_of_work(&self, header: &Header) -> Result<(), HeaderError> {
+        let hash = header.hash();
+        if hash > header.difficulty || header.difficulty != self.params.difficulty {
+            return Err(HeaderError::InvalidProofOfWork(hash));
+        }
+        Ok(())
+    }
+
+    /// Get the last header's hash of the longest header chain, leaving out withheld blocks
+    pub fn best_header(&self) -> H256 {
+        let (hash, _) = self
+            .headers
+            .iter()
+            .filter(|(hash, _)| !self.withheld.contains(hash))
+            .max_by_key(|(_, (_, height))| *height)
+            .expect("failed");
+        *hash
     }
 
-    /// Get the last block's hash of the longest chain
+    /// Hashes of the ancestors of `hash` starting with itself, one step apart first and then
+    /// exponentially farther apart, always ending with the genesis block
+    pub fn locator_from(&self, hash: H256) -> Vec<H256> {
+        let mut locator = Vec::new();
+        let mut hash = hash;
+        let mut step = 1;
+        loop {
+            locator.push(hash);
+            let height = self.headers[&hash].1;
+            if height == 0 {
+                return locator;
+            }
+            if locator.len() >= 10 {
+                step *= 2;
+            }
+            // walk back `step` headers, stopping at the genesis block
+            for _ in 0..step.min(height) {
+                hash = self.headers[&hash].0.parent;
+            }
+        }
+    }
+
+    /// Locator of our best header chain, sent to peers to find where our chains fork
+    pub fn locator(&self) -> Vec<H256> {
+        self.locator_from(self.best_header())
+    }
+
+    /// Headers of our longest public chain following the first hash of `locator` on it, at most
+    /// `max`
+    pub fn headers_after(&self, locator: &[H256], max: usize) -> Vec<Header> {
+        // the longest chain, from the tip down
+        let mut chain = Vec::new();
+        let mut hash = self.tip();
+        loop {
+            chain.push(hash);
+            if self.heights[&hash] == 0 {
+                break;
+            }
+            hash = self.blocks[&hash].header.parent;
+        }
+        // the fork point is the first locator entry on our chain, or the genesis block
+        let fork = locator
+            .iter()
+            .find_map(|hash| chain.iter().position(|h| h == hash))
+            .unwrap_or(chain.len() - 1);
+        chain[..fork]
+            .iter()
+            .rev()
+            .take(max)
+            .map(|hash| self.blocks[hash].header.clone())
+            .collect()
+    }
+
+    /// Hashes of the blocks missing on the way to the best header, lowest first
+    pub fn missing_blocks(&self, max: usize) -> Vec<H256> {
+        let mut missing = Vec::new();
+        let mut hash = self.best_header();
+        while !self.blocks.contains_key(&hash) {
+            missing.push(hash);
+            hash = self.headers[&hash].0.parent;
+        }
+        missing.reverse();
+        missing.truncate(max);
+        missing
+    }
+
+    /// Get the hash of the genesis block
+    pub fn genesis(&self) -> H256 {
+        let (hash, _) = self.heights.iter().find(|(_, height)| **height == 0).expect("failed");
+        *hash
+    }
+
+    /// Get the last block's hash of the longest public chain
     pub fn tip(&self) -> H256 {
-        let mut tip = [0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0].into();
-        let mut last = 0;
-        for(hash, height) in &self.heights{
-            if height >= &last{
-                last = height.clone();
-                tip = *hash;
+        self.tip
+    }
+
+    /// The state at the tip
+    pub fn state(&self) -> &State {
+        &self.states[&self.tip]
+    }
+
+    /// The state after the block `hash`, if we have the block
+    pub fn state_at(&self, hash: &H256) -> Option<&State> {
+        self.states.get(hash)
+    }
+
+    /// Blocks on the chain ending at `from` that are not on the chain ending at `to`, highest
+    /// first
+    pub fn abandoned(&self, from: H256, to: H256) -> Vec<H256> {
+        let (mut from, mut to) = (from, to);
+        let mut abandoned = Vec::new();
+        while from != to {
+            // step back on the higher chain, or on both at the same height
+            if self.heights[&from] >= self.heights[&to] {
+                abandoned.push(from);
+                from = self.blocks[&from].header.parent;
+            } else {
+                to = self.blocks[&to].header.parent;
             }
         }
-        return tip;
+        abandoned
     }
 
     /// Get all blocks in longest chain
@@ -110,7 +335,54 @@ impl Blockchain {
 
 #[cfg(any(test, test_utilities))]
 mod tests {
-    // use super::*;
+    use super::*;
+
+    #[test]
+    fn headers_sync_from_the_fork_point() {
+        let mut ours = Blockchain::with_params(ChainParams::regtest());
+        let mut theirs = Blockchain::with_params(ChainParams::regtest());
+        let genesis = ours.tip();
+        let difficulty = ours.params.difficulty;
+        let mut hashes = vec![genesis];
+        for i in 0..30 {
+            let header = Header::new(hashes[i], i as u32, difficulty, 0, [0; 32].into());
+            let block = Block { header, data: vec![] };
+            theirs.insert(&block, State::new());
+            // we have the first 20 blocks
+            if i < 20 {
+                ours.insert(&block, State::new());
+            }
+            hashes.push(block.hash());
+        }
+
+        let locator = ours.locator();
+        assert_eq!(locator.len(), 13);
+        assert_eq!(locator[..10], hashes[11..21].iter().rev().copied().collect::<Vec<_>>()[..]);
+        assert_eq!(locator.last(), Some(&genesis));
+
+        let headers = theirs.headers_after(&locator, 8);
+        assert_eq!(headers[0].parent, hashes[20]);
+        assert_eq!(headers.len(), 8);
+        for header in &headers {
+            assert_eq!(ours.insert_header(header), Ok(true));
+        }
+        assert_eq!(ours.best_header(), hashes[28]);
+        assert_eq!(ours.missing_blocks(5), hashes[21..26].to_vec());
+        // a header out of order or below its target is refused
+        let orphan = Header::new(hashes[29], 0, difficulty, 0, [0; 32].into());
+        assert_eq!(ours.insert_header(&orphan), Err(HeaderError::UnknownParent(hashes[29])));
+        // the proof of work is checked before the parent is looked up
+        let mut junk = orphan.clone();
+        junk.difficulty = [0; 32].into();
+        assert_eq!(ours.insert_header(&junk), Err(HeaderError::InvalidProofOfWork(junk.hash())));
+        let mut harder = Header::new(hashes[28], 0, difficulty, 0, [1; 32].into());
+        harder.difficulty = [0; 32].into();
+        assert_eq!(
+            ours.insert_header(&harder),
+            Err(HeaderError::InvalidProofOfWork(harder.hash()))
+        );
+    }
+    // use crate::transaction::conversion;
     // use crate::block::test::generate_random_block;
     // use crate::crypto::hash::Hashable;
 
@@ -57,6 +57,18 @@ impl std::fmt::Display for H160 {
     }
 }
 
+impl std::str::FromStr for H160 {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes = hex::decode(s).map_err(|e| e.to_string())?;
+        let bytes: [u8; 20] = bytes[..]
+            .try_into()
+            .map_err(|_| format!("expected 20 bytes, got {}", bytes.len()))?;
+        Ok(H160(bytes))
+    }
+}
+
 impl std::fmt::Debug for H256 {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(
@@ -7,3 +7,10 @@ pub fn random() -> Ed25519KeyPair {
     let pkcs8_bytes = Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
     Ed25519KeyPair::from_pkcs8(pkcs8_bytes.as_ref().into()).unwrap()
 }
+
+/// Key pair of the `index`th account funded in the genesis state. Every node derives the same
+/// keys, so anyone can spend these coins.
+pub fn ico(index: u8) -> Ed25519KeyPair {
+    let seed = ring::digest::digest(&ring::digest::SHA256, &[b'i', b'c', b'o', index]);
+    Ed25519KeyPair::from_seed_unchecked(seed.as_ref()).unwrap()
+}
@@ -6,21 +6,29 @@ pub mod api;
 pub mod block;
 pub mod blockchain;
 pub mod crypto;
+pub mod mempool;
 pub mod miner;
 pub mod network;
+pub mod params;
 pub mod transaction;
 
 use clap::clap_app;
 use crossbeam::channel;
-use log::{error, info};
+use log::{error, info, warn};
 use api::Server as ApiServer;
-use network::{server, worker};
+use network::addrbook::{self, AddressBook};
+use network::session::NodeKey;
+use network::{frame, handshake, manager, server, worker};
+use std::fs;
 use std::net;
+use std::path::PathBuf;
 use std::process;
 use std::thread;
 use std::time;
 use crate::blockchain::*;
-use crate::transaction::*;
+use crate::crypto::hash::H160;
+use crate::mempool::Mempool;
+use crate::params::ChainParams;
 use std::sync::{Arc, Mutex};
 
 fn main() {
@@ -32,7 +40,19 @@ fn main() {
      (@arg peer_addr: --p2p [ADDR] default_value("127.0.0.1:6000") "Sets the IP address and the port of the P2P server")
      (@arg api_addr: --api [ADDR] default_value("127.0.0.1:7000") "Sets the IP address and the port of the API server")
      (@arg known_peer: -c --connect ... [PEER] "Sets the peers to connect to at start")
+     (@arg encrypt: --encrypt "Encrypts and authenticates P2P connections, peers have to enable it as well")
+     (@arg outbound_peers: --("outbound-peers") [INT] default_value("8") "Sets the number of outgoing peer connections the node keeps open")
      (@arg p2p_workers: --("p2p-workers") [INT] default_value("4") "Sets the number of worker threads for P2P server")
+     (@arg mempool_max_txs: --("mempool-max-txs") [INT] default_value("5000") "Sets the maximum number of pending transactions")
+     (@arg miner_threads: --("miner-threads") [INT] default_value("1") "Sets the number of threads searching for proof of work")
+     (@arg regtest: --regtest "Runs a regression test network with trivial difficulty and an empty genesis state")
+     (@arg miner_address: --("miner-address") [ADDR] "Sets the address credited with the reward of mined blocks")
+     (@arg mining_strategy: --("mining-strategy") [NAME] possible_values(&["honest", "selfish"]) default_value("honest") "Sets where the miner builds and when it announces its blocks")
+     (@arg selfish_release_lead: --("selfish-release-lead") [INT] default_value("1") "Sets the lead at or below which a selfish miner reveals its private branch")
+     (@arg mine_empty: --("mine-empty-blocks") "Mines blocks without transactions instead of waiting for the mempool")
+     (@arg data_dir: --("data-dir") [DIR] "Sets the directory where node data is persisted across restarts")
+     (@arg mempool_save_interval: --("mempool-save-interval") [SECS] default_value("60") "Sets how often the mempool is saved to the data directory")
+     (@arg mempool_max_bytes: --("mempool-max-bytes") [INT] default_value("1048576") "Sets the maximum total size of pending transactions in bytes")
     )
     .get_matches();
 
@@ -63,16 +83,174 @@ fn main() {
     // create channels between server and worker
     let (msg_tx, msg_rx) = channel::unbounded();
 
-    // start the p2p server
-    let (server_ctx, server) = server::new(p2p_addr, msg_tx).unwrap();
-    server_ctx.start().unwrap();
-
     // create new blockchain
     // only have the genisis block
-    let mut blockchain = Arc::new(Mutex::new(Blockchain::new()));
+    let regtest = matches.is_present("regtest");
+    let params = if regtest {
+        ChainParams::regtest()
+    } else {
+        ChainParams::main()
+    };
+    info!("Running on the {} network", params.name);
+    let frame_config = frame::Config::new(&params);
+    let mut blockchain = Arc::new(Mutex::new(Blockchain::with_params(params)));
+
+    // create the data directory
+    let data_dir = matches.value_of("data_dir").map(PathBuf::from);
+    if let Some(dir) = &data_dir {
+        fs::create_dir_all(dir).unwrap_or_else(|e| {
+            error!("Error creating data directory {}: {}", dir.display(), e);
+            process::exit(1);
+        });
+    }
+
+    // restore the peers we knew about in the previous run
+    let addrbook_path = data_dir.as_ref().map(|dir| dir.join("peers.dat"));
+    let addrbook = match addrbook_path.as_ref().filter(|p| p.exists()) {
+        Some(path) => AddressBook::load(path).unwrap_or_else(|e| {
+            warn!("Error loading address book from {}: {}", path.display(), e);
+            AddressBook::new()
+        }),
+        None => AddressBook::new(),
+    };
+    info!("Address book has {} known peers", addrbook.len());
+    let addrbook = Arc::new(Mutex::new(addrbook));
+    if let Some(path) = addrbook_path.clone() {
+        let addrbook = Arc::clone(&addrbook);
+        thread::spawn(move || loop {
+            thread::sleep(time::Duration::from_secs(addrbook::SAVE_INTERVAL_SECS));
+            if let Err(e) = addrbook.lock().unwrap().save(&path) {
+                warn!("Error saving address book to {}: {}", path.display(), e);
+            }
+        });
+    }
+
+    // the identity peers see in encrypted sessions, kept across restarts with a data directory
+    let node_key = if matches.is_present("encrypt") {
+        let node_key = match &data_dir {
+            Some(dir) => NodeKey::load_or_create(&dir.join("node.key")).unwrap_or_else(|e| {
+                error!("Error loading node key: {}", e);
+                process::exit(1);
+            }),
+            None => NodeKey::random(),
+        };
+        info!("Node ID {}", node_key.id());
+        Some(Arc::new(node_key))
+    } else {
+        None
+    };
+
+    // start the p2p server
+    let local = handshake::Local::new(&blockchain, p2p_addr);
+    let (server_ctx, server) = server::new(p2p_addr, msg_tx, frame_config, node_key, local, &addrbook).unwrap();
+    server_ctx.start().unwrap();
     // create new mempool
-    // empty hashmap
-    let mut mempool = Arc::new(Mutex::new(Mempool::new()));
+    let mempool_max_txs = matches
+        .value_of("mempool_max_txs")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing mempool transaction limit: {}", e);
+            process::exit(1);
+        });
+    let mempool_max_bytes = matches
+        .value_of("mempool_max_bytes")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing mempool size limit: {}", e);
+            process::exit(1);
+        });
+    let mut mempool = Arc::new(Mutex::new(Mempool::with_limits(mempool_max_txs, mempool_max_bytes)));
+
+    // restore the mempool saved by the previous run
+    let mempool_path = data_dir.as_ref().map(|dir| dir.join("mempool.dat"));
+    if let Some(path) = mempool_path.as_ref().filter(|p| p.exists()) {
+        match mempool::load(path) {
+            Ok(saved) => {
+                let total = saved.len();
+                let blc = blockchain.lock().unwrap();
+                // the chain may have moved on, only take back what is still valid at the tip
+                let restored = mempool.lock().unwrap().restore(saved, blc.state());
+                drop(blc);
+                if restored < total {
+                    warn!(
+                        "Dropped {} of {} saved mempool transactions, they are not valid at the tip",
+                        total - restored,
+                        total
+                    );
+                }
+                info!("Restored {} of {} saved mempool transactions", restored, total);
+            }
+            Err(e) => warn!("Error loading mempool from {}: {}", path.display(), e),
+        }
+    }
+
+    // save the mempool periodically
+    let mempool_save_interval = matches
+        .value_of("mempool_save_interval")
+        .unwrap()
+        .parse::<u64>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing mempool save interval: {}", e);
+            process::exit(1);
+        });
+    if let Some(path) = mempool_path.clone() {
+        let mempool = Arc::clone(&mempool);
+        thread::spawn(move || loop {
+            thread::sleep(time::Duration::from_secs(mempool_save_interval));
+            // the file is written without holding up the mempool
+            let transactions = mempool.lock().unwrap().transactions();
+            if let Err(e) = mempool::save_transactions(&transactions, &path) {
+                warn!("Error saving mempool to {}: {}", path.display(), e);
+            }
+        });
+    }
+
+    // create the miner, started once everything it depends on is running
+    let mine_empty = matches.is_present("mine_empty");
+    let miner_address = match matches.value_of("miner_address") {
+        Some(address) => address.parse::<H160>().unwrap_or_else(|e| {
+            error!("Error parsing miner address: {}", e);
+            process::exit(1);
+        }),
+        None => H160::default(),
+    };
+    let miner_threads = matches
+        .value_of("miner_threads")
+        .unwrap()
+        .parse::<usize>()
+        .ok()
+        .filter(|n| *n > 0)
+        .unwrap_or_else(|| {
+            error!("Error parsing miner threads: expected a positive integer");
+            process::exit(1);
+        });
+    let strategy: Box<dyn miner::strategy::Strategy> =
+        match matches.value_of("mining_strategy").unwrap() {
+            "selfish" => {
+                let release_lead = matches
+                    .value_of("selfish_release_lead")
+                    .unwrap()
+                    .parse::<u32>()
+                    .unwrap_or_else(|e| {
+                        error!("Error parsing selfish release lead: {}", e);
+                        process::exit(1);
+                    });
+                Box::new(miner::strategy::Selfish::new(release_lead))
+            }
+            _ => Box::new(miner::strategy::Honest),
+        };
+    let (miner_ctx, miner) = miner::new(
+        &server,
+        // miner share the ownership
+        &blockchain,
+        &mempool,
+        mine_empty,
+        miner_address,
+        miner_threads,
+        strategy,
+    );
 
     // start the worker
     let p2p_workers = matches
@@ -90,69 +268,83 @@ fn main() {
         // worker process share the ownership
         &blockchain,
         &mempool,
+        &miner,
+        &addrbook,
     );
     worker_ctx.start();
 
-
     // start the miner
-    let (miner_ctx, miner) = miner::new(
-        &server,
-        // miner share the ownership
-        &blockchain,
-        &mempool,
-    );
     miner_ctx.start();
 
-    // start the tx generator
-    let tx_ctx = transaction::new(
-        &server, 
-        &blockchain,
-        &mempool,
-    );
-    tx_ctx.start();
-
-    // connect to known peers
-    if let Some(known_peers) = matches.values_of("known_peer") {
-        let known_peers: Vec<String> = known_peers.map(|x| x.to_owned()).collect();
-        let server = server.clone();
-        thread::spawn(move || {
-            for peer in known_peers {
-                loop {
-                    let addr = match peer.parse::<net::SocketAddr>() {
-                        Ok(x) => x,
-                        Err(e) => {
-                            error!("Error parsing peer address {}: {}", &peer, e);
-                            break;
-                        }
-                    };
-                    match server.connect(addr) {
-                        Ok(_) => {
-                            info!("Connected to outgoing peer {}", &addr);
-                            break;
-                        }
-                        Err(e) => {
-                            error!(
-                                "Error connecting to peer {}, retrying in one second: {}",
-                                addr, e
-                            );
-                            thread::sleep(time::Duration::from_millis(1000));
-                            continue;
-                        }
-                    }
-                }
-            }
-        });
+    // start the tx generator, regtest keeps the state deterministic
+    if !regtest {
+        let tx_ctx = transaction::new(
+            &server, 
+            &blockchain,
+            &mempool,
+            &miner,
+        );
+        tx_ctx.start();
+    }
+
+    // keep connected to the peers given on the command line and those we know of
+    let mut seeds = vec![];
+    for peer in matches.values_of("known_peer").into_iter().flatten() {
+        match peer.parse::<net::SocketAddr>() {
+            Ok(addr) => seeds.push(addr),
+            Err(e) => error!("Error parsing peer address {}: {}", peer, e),
+        }
     }
+    let outbound_peers = matches
+        .value_of("outbound_peers")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing outbound peer count: {}", e);
+            process::exit(1);
+        });
+    let manager_ctx = manager::new(&server, &addrbook, seeds, outbound_peers, p2p_addr);
+    manager_ctx.start();
 
+    // templates for miners outside of the node
+    let getwork = miner::getwork::new(&server, &blockchain, &mempool, &miner, miner_address);
+    let pool = miner::pool::new(&blockchain, &getwork, miner_address);
 
     // start the API server
+    let (shutdown_tx, shutdown_rx) = channel::unbounded();
     ApiServer::start(
         api_addr,
         &miner,
+        &getwork,
+        &pool,
         &server,
+        &mempool,
+        &shutdown_tx,
     );
 
-    loop {
-        std::thread::park();
+    // Ctrl-C and SIGTERM shut down as cleanly as the API does
+    let signal_tx = shutdown_tx.clone();
+    if let Err(e) = ctrlc::set_handler(move || {
+        let _ = signal_tx.send(());
+    }) {
+        warn!("Error installing the signal handler: {}", e);
+    }
+
+    // wait for a shutdown request, then persist what would otherwise be lost
+    shutdown_rx.recv().unwrap();
+    info!("Shutting down");
+    miner.exit();
+    if let Some(path) = &mempool_path {
+        let transactions = mempool.lock().unwrap().transactions();
+        match mempool::save_transactions(&transactions, path) {
+            Ok(()) => info!("Saved mempool to {}", path.display()),
+            Err(e) => error!("Error saving mempool to {}: {}", path.display(), e),
+        }
+    }
+    if let Some(path) = &addrbook_path {
+        match addrbook.lock().unwrap().save(path) {
+            Ok(()) => info!("Saved address book to {}", path.display()),
+            Err(e) => error!("Error saving address book to {}: {}", path.display(), e),
+        }
     }
 }
@@ -0,0 +1,585 @@
+use crate::crypto::hash::{H160, H256};
+use crate::blockchain::State;
+use crate::transaction::{check_against_state, SignedTransaction};
+use log::warn;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BinaryHeap, HashMap};
+use std::fs;
+use std::path::Path;
+
+/// Default maximum number of pending transactions.
+pub const DEFAULT_MAX_COUNT: usize = 5000;
+/// Default maximum total serialized size of pending transactions, in bytes.
+pub const DEFAULT_MAX_BYTES: usize = 1 << 20;
+/// A replacement has to pay at least this much more than the transaction it replaces.
+pub const MIN_FEE_BUMP: u32 = 1;
+/// A replacement has to pay at least this percentage more than the transaction it replaces.
+pub const MIN_FEE_BUMP_PERCENT: u32 = 10;
+
+/// Reasons for refusing a transaction into the mempool.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum MempoolError {
+    /// The account nonce has already been used on chain.
+    Stale,
+    /// The same transaction is already pending.
+    Duplicate,
+    /// Another transaction of the same sender already occupies this nonce, and the new one does not
+    /// pay enough more to replace it.
+    Conflict,
+    /// The mempool is full and the transaction pays less than everything in it.
+    Full,
+}
+
+/// A pending transaction together with the bookkeeping used for eviction.
+struct Entry {
+    tx: SignedTransaction,
+    size: usize,
+    /// Insertion order, smaller is older.
+    sequence: u64,
+}
+
+/// Pending transactions of one sender, keyed by account nonce.
+///
+/// `ready` always holds a gapless run of nonces starting right after the on-chain account nonce,
+/// `future` holds everything that has to wait for a missing nonce to arrive first.
+#[derive(Debug, Default)]
+struct AccountQueue {
+    ready: BTreeMap<u8, H256>,
+    future: BTreeMap<u8, H256>,
+}
+
+impl AccountQueue {
+    fn get(&self, nonce: u8) -> Option<&H256> {
+        self.ready.get(&nonce).or_else(|| self.future.get(&nonce))
+    }
+
+    fn is_empty(&self) -> bool {
+        self.ready.is_empty() && self.future.is_empty()
+    }
+
+    /// The nonce that would extend the ready queue, `None` once the nonces are used up.
+    fn next_nonce(&self, state_nonce: u8) -> Option<u8> {
+        match self.ready.keys().next_back() {
+            Some(last) => last.checked_add(1),
+            None => state_nonce.checked_add(1),
+        }
+    }
+
+    /// Drop every nonce already used on chain and rebuild the ready queue from `state_nonce`.
+    /// Returns the hashes of the dropped transactions.
+    fn reset(&mut self, state_nonce: u8) -> Vec<H256> {
+        let mut dropped = Vec::new();
+        let mut pending = std::mem::take(&mut self.future);
+        pending.append(&mut self.ready);
+        for (nonce, hash) in pending {
+            if nonce <= state_nonce {
+                dropped.push(hash);
+            } else {
+                self.future.insert(nonce, hash);
+            }
+        }
+        self.promote(state_nonce);
+        dropped
+    }
+
+    /// Move transactions from the future queue to the ready queue as long as nonces are contiguous.
+    fn promote(&mut self, state_nonce: u8) {
+        while let Some(next) = self.next_nonce(state_nonce) {
+            match self.future.remove(&next) {
+                Some(hash) => {
+                    self.ready.insert(next, hash);
+                }
+                None => break,
+            }
+        }
+    }
+
+    /// Remove the transaction at `nonce`. Ready transactions after it can no longer be mined before
+    /// the gap is filled again, so they go back to the future queue.
+    fn remove(&mut self, nonce: u8) {
+        if self.ready.remove(&nonce).is_some() {
+            let mut after = self.ready.split_off(&nonce);
+            self.future.append(&mut after);
+        } else {
+            self.future.remove(&nonce);
+        }
+    }
+}
+
+/// The minimum fee a transaction has to pay to replace a pending one paying `old_fee`.
+pub fn replacement_fee(old_fee: u32) -> u32 {
+    // in u64, so large fees don't overflow
+    let percent = u64::from(old_fee) * u64::from(MIN_FEE_BUMP_PERCENT) / 100;
+    let bump = std::cmp::max(MIN_FEE_BUMP, percent.min(u64::from(u32::MAX)) as u32);
+    old_fee.saturating_add(bump)
+}
+
+/// Write the pending transactions, taken with `Mempool::transactions`, to `path`. Taking them first
+/// keeps the mempool free while the file is written. The file is replaced atomically, so a crash
+/// while saving leaves the previous copy intact.
+pub fn save_transactions(transactions: &[SignedTransaction], path: &Path) -> std::io::Result<()> {
+    let bytes = bincode::serialize(transactions).map_err(std::io::Error::other)?;
+    let tmp_path = path.with_extension("tmp");
+    fs::write(&tmp_path, bytes)?;
+    fs::rename(&tmp_path, path)
+}
+
+/// Valid transactions that have not been included in the blockchain yet, keyed by txid.
+///
+/// The mempool is bounded both in transaction count and in total serialized bytes. When it is full,
+/// the entry with the lowest fee is evicted, the oldest one first among equal fees.
+pub struct Mempool {
+    valid_tx: HashMap<H256, Entry>,
+    queues: HashMap<H160, AccountQueue>,
+    max_count: usize,
+    max_bytes: usize,
+    total_bytes: usize,
+    next_sequence: u64,
+}
+
+impl Default for Mempool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Mempool {
+    pub fn new() -> Self {
+        Self::with_limits(DEFAULT_MAX_COUNT, DEFAULT_MAX_BYTES)
+    }
+
+    pub fn with_limits(max_count: usize, max_bytes: usize) -> Self {
+        Mempool {
+            valid_tx: HashMap::new(),
+            queues: HashMap::new(),
+            max_count,
+            max_bytes,
+            total_bytes: 0,
+            next_sequence: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.valid_tx.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.valid_tx.is_empty()
+    }
+
+    /// Total serialized size of the pending transactions.
+    pub fn size_in_bytes(&self) -> usize {
+        self.total_bytes
+    }
+
+    pub fn contains(&self, hash: &H256) -> bool {
+        self.valid_tx.contains_key(hash)
+    }
+
+    pub fn get(&self, hash: &H256) -> Option<&SignedTransaction> {
+        self.valid_tx.get(hash).map(|entry| &entry.tx)
+    }
+
+    /// Insert a transaction whose sender currently has account nonce `state_nonce` on chain.
+    ///
+    /// The transaction lands in the sender's ready queue if it is the next nonce in line, and in the
+    /// future queue otherwise. If the sender already has a transaction at this nonce, it is replaced
+    /// when the new one pays at least `replacement_fee` of the old fee. Returns the txid it is stored
+    /// under.
+    pub fn insert(&mut self, tx: SignedTransaction, state_nonce: u8) -> Result<H256, MempoolError> {
+        let nonce = tx.tx.account_nonce;
+        if nonce <= state_nonce {
+            return Err(MempoolError::Stale);
+        }
+        let hash = tx.txid();
+        if self.valid_tx.contains_key(&hash) {
+            return Err(MempoolError::Duplicate);
+        }
+        let sender = tx.sender();
+        self.update_nonce(&sender, state_nonce);
+        // replace-by-fee: a conflicting transaction is evicted if the new one pays enough more
+        let existing = self.queues.get(&sender).and_then(|q| q.get(nonce)).copied();
+        let replaced = match existing {
+            Some(existing) => {
+                let old_fee = self.valid_tx[&existing].tx.tx.fee;
+                if tx.tx.fee < replacement_fee(old_fee) {
+                    return Err(MempoolError::Conflict);
+                }
+                self.remove(&existing)
+            }
+            None => None,
+        };
+        let size = bincode::serialize(&tx).unwrap().len();
+        if let Err(e) = self.make_room(size, tx.tx.fee) {
+            if let Some(old) = replaced {
+                let txid = old.txid();
+                if let Err(restore) = self.insert(old, state_nonce) {
+                    warn!("Lost replaced transaction {} while restoring it: {:?}", txid, restore);
+                }
+            }
+            return Err(e);
+        }
+
+        let queue = self.queues.entry(sender).or_default();
+        queue.future.insert(nonce, hash);
+        queue.promote(state_nonce);
+        let entry = Entry {
+            tx,
+            size,
+            sequence: self.next_sequence,
+        };
+        self.next_sequence += 1;
+        self.total_bytes += size;
+        self.valid_tx.insert(hash, entry);
+        Ok(hash)
+    }
+
+    /// Remove a pending transaction.
+    pub fn remove(&mut self, hash: &H256) -> Option<SignedTransaction> {
+        let entry = self.valid_tx.remove(hash)?;
+        self.total_bytes -= entry.size;
+        let sender = entry.tx.sender();
+        if let Some(queue) = self.queues.get_mut(&sender) {
+            queue.remove(entry.tx.tx.account_nonce);
+            if queue.is_empty() {
+                self.queues.remove(&sender);
+            }
+        }
+        Some(entry.tx)
+    }
+
+    /// Evict entries until a transaction of `size` bytes paying `fee` fits. Fails without evicting
+    /// anything if the new transaction would itself be the first one to go.
+    fn make_room(&mut self, size: usize, fee: u32) -> Result<(), MempoolError> {
+        if size > self.max_bytes {
+            return Err(MempoolError::Full);
+        }
+        let mut victims = Vec::new();
+        let mut count = self.valid_tx.len();
+        let mut bytes = self.total_bytes;
+        let mut candidates: Vec<(&H256, &Entry)> = self.valid_tx.iter().collect();
+        candidates.sort_by_key(|(_, entry)| (entry.tx.tx.fee, entry.sequence));
+        let mut candidates = candidates.into_iter();
+        while count + 1 > self.max_count || bytes + size > self.max_bytes {
+            match candidates.next() {
+                Some((hash, entry)) if entry.tx.tx.fee < fee => {
+                    victims.push(*hash);
+                    count -= 1;
+                    bytes -= entry.size;
+                }
+                _ => return Err(MempoolError::Full),
+            }
+        }
+        for hash in victims {
+            self.remove(&hash);
+        }
+        Ok(())
+    }
+
+    /// The nonce a new transaction from `sender` should use to be ready right away, `None` if
+    /// the account has used up its nonces.
+    pub fn next_nonce(&self, sender: &H160, state_nonce: u8) -> Option<u8> {
+        match self.queues.get(sender) {
+            Some(queue) => queue.next_nonce(state_nonce),
+            None => state_nonce.checked_add(1),
+        }
+    }
+
+    /// Notify the mempool that `sender` now has account nonce `state_nonce` on chain.
+    /// Transactions using an old nonce are dropped, and waiting ones are promoted if the gap is filled.
+    pub fn update_nonce(&mut self, sender: &H160, state_nonce: u8) {
+        if let Some(queue) = self.queues.get_mut(sender) {
+            for dropped in queue.reset(state_nonce) {
+                if let Some(entry) = self.valid_tx.remove(&dropped) {
+                    self.total_bytes -= entry.size;
+                }
+            }
+            if queue.is_empty() {
+                self.queues.remove(sender);
+            }
+        }
+    }
+
+    /// All pending transactions, oldest first.
+    pub fn transactions(&self) -> Vec<SignedTransaction> {
+        let mut entries: Vec<&Entry> = self.valid_tx.values().collect();
+        entries.sort_by_key(|entry| entry.sequence);
+        entries.into_iter().map(|entry| entry.tx.clone()).collect()
+    }
+
+    /// Take back the transactions an earlier run saved, inserting only those still valid on
+    /// `state`. Returns how many made it.
+    pub fn restore(&mut self, saved: Vec<SignedTransaction>, state: &State) -> usize {
+        let mut restored = 0;
+        for tx in saved {
+            if let Some(state_nonce) = check_against_state(&tx, state) {
+                if self.insert(tx, state_nonce).is_ok() {
+                    restored += 1;
+                }
+            }
+        }
+        restored
+    }
+
+    /// Ready transactions for a block, at most `max_count` of them taking at most `max_bytes`
+    /// serialized. Picks the highest fee rate first while keeping each sender's nonces in order,
+    /// and stops taking a sender's transactions once they would spend more than its balance in
+    /// `state`.
+    pub fn ready_transactions(
+        &self,
+        state: &HashMap<H160, (u8, u32)>,
+        max_count: usize,
+        max_bytes: usize,
+    ) -> Vec<SignedTransaction> {
+        // every sender's ready transactions, lowest nonce first. The mempool follows the tip, so on
+        // another branch a sender's queue is only usable if it starts right after its nonce there.
+        let mut queues: Vec<_> = self
+            .queues
+            .iter()
+            .map(|(sender, q)| {
+                let next = state.get(sender).and_then(|(nonce, _)| nonce.checked_add(1));
+                let ready: Vec<&H256> = match next {
+                    Some(next) if q.ready.contains_key(&next) => {
+                        q.ready.range(next..).map(|(_, hash)| hash).collect()
+                    }
+                    _ => Vec::new(),
+                };
+                ready.into_iter()
+            })
+            .collect();
+        // what each sender has left to spend in this block
+        let mut balances: Vec<u32> = self
+            .queues
+            .keys()
+            .map(|sender| state.get(sender).map_or(0, |(_, balance)| *balance))
+            .collect();
+        let mut candidates = BinaryHeap::new();
+        for (queue, hashes) in queues.iter_mut().enumerate() {
+            if let Some(hash) = hashes.next() {
+                candidates.push(Candidate { entry: &self.valid_tx[hash], queue });
+            }
+        }
+        let mut result = Vec::new();
+        let mut bytes = 0;
+        while let Some(Candidate { entry, queue }) = candidates.pop() {
+            if result.len() == max_count {
+                break;
+            }
+            // the sender's later nonces cannot go in without this one
+            if bytes + entry.size > max_bytes {
+                continue;
+            }
+            match entry.tx.tx.cost().and_then(|cost| balances[queue].checked_sub(cost)) {
+                Some(left) => balances[queue] = left,
+                None => continue,
+            }
+            bytes += entry.size;
+            result.push(entry.tx.clone());
+            if let Some(hash) = queues[queue].next() {
+                candidates.push(Candidate { entry: &self.valid_tx[hash], queue });
+            }
+        }
+        result
+    }
+}
+
+/// The lowest ready nonce of one sender, ordered by fee rate, then age.
+struct Candidate<'a> {
+    entry: &'a Entry,
+    /// Index of the sender's ready transactions still to pick from
+    queue: usize,
+}
+
+impl Ord for Candidate<'_> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // fee / size compared without division
+        let rate = u64::from(self.entry.tx.tx.fee) * other.entry.size as u64;
+        let other_rate = u64::from(other.entry.tx.tx.fee) * self.entry.size as u64;
+        rate.cmp(&other_rate)
+            .then_with(|| other.entry.sequence.cmp(&self.entry.sequence))
+    }
+}
+
+impl PartialOrd for Candidate<'_> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Candidate<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Candidate<'_> {}
+
+/// Read the transactions written by `save_transactions`. They are not checked in any way, they go
+/// back in through `Mempool::restore`.
+pub fn load(path: &Path) -> std::io::Result<Vec<SignedTransaction>> {
+    let bytes = fs::read(path)?;
+    bincode::deserialize(&bytes).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::key_pair;
+    use crate::transaction::{sign, Transaction};
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+
+    /// A state in which each of `keys` has `balance`
+    fn funded(keys: &[&Ed25519KeyPair], balance: u32) -> HashMap<H160, (u8, u32)> {
+        keys.iter()
+            .map(|key| (signed(key, 0, 0).sender(), (0, balance)))
+            .collect()
+    }
+
+    fn signed(key: &Ed25519KeyPair, nonce: u8, fee: u32) -> SignedTransaction {
+        let tx = Transaction::new([0; 20].into(), 1, nonce, fee);
+        let signature = sign(&tx, key).as_ref().to_vec();
+        SignedTransaction::new(tx, signature, key.public_key().as_ref().to_vec())
+    }
+
+    #[test]
+    fn future_promoted_when_gap_filled() {
+        let key = key_pair::random();
+        let mut mempool = Mempool::new();
+        mempool.insert(signed(&key, 7, 1), 4).unwrap();
+        mempool.insert(signed(&key, 6, 1), 4).unwrap();
+        let mut state = funded(&[&key], 100);
+        for account in state.values_mut() {
+            account.0 = 4;
+        }
+        assert!(mempool.ready_transactions(&state, 10, usize::MAX).is_empty());
+        mempool.insert(signed(&key, 5, 1), 4).unwrap();
+        let nonces: Vec<u8> = mempool
+            .ready_transactions(&state, 10, usize::MAX)
+            .iter()
+            .map(|t| t.tx.account_nonce)
+            .collect();
+        assert_eq!(nonces, vec![5, 6, 7]);
+        assert_eq!(mempool.insert(signed(&key, 4, 1), 4), Err(MempoolError::Stale));
+    }
+
+    #[test]
+    fn update_nonce_drops_included() {
+        let key = key_pair::random();
+        let mut mempool = Mempool::new();
+        mempool.insert(signed(&key, 2, 1), 1).unwrap();
+        mempool.insert(signed(&key, 3, 1), 1).unwrap();
+        mempool.update_nonce(&signed(&key, 2, 1).sender(), 2);
+        assert_eq!(mempool.len(), 1);
+        assert_eq!(mempool.next_nonce(&signed(&key, 2, 1).sender(), 2), Some(4));
+    }
+
+    #[test]
+    fn same_nonce_conflicts() {
+        let key = key_pair::random();
+        let mut mempool = Mempool::new();
+        mempool.insert(signed(&key, 1, 20), 0).unwrap();
+        assert_eq!(mempool.insert(signed(&key, 1, 21), 0), Err(MempoolError::Conflict));
+        assert_eq!(mempool.len(), 1);
+    }
+
+    #[test]
+    fn higher_fee_replaces() {
+        let key = key_pair::random();
+        let mut mempool = Mempool::new();
+        let original = mempool.insert(signed(&key, 1, 20), 0).unwrap();
+        mempool.insert(signed(&key, 2, 1), 0).unwrap();
+        let replacement = mempool.insert(signed(&key, 1, 22), 0).unwrap();
+        assert!(!mempool.contains(&original));
+        assert!(mempool.contains(&replacement));
+        let nonces: Vec<u8> = mempool
+            .ready_transactions(&funded(&[&key], 100), 10, usize::MAX)
+            .iter()
+            .map(|t| t.tx.account_nonce)
+            .collect();
+        assert_eq!(nonces, vec![1, 2]);
+        assert_eq!(replacement_fee(1_000_000_000), 1_100_000_000);
+        assert_eq!(replacement_fee(u32::MAX - 1), u32::MAX);
+    }
+
+    #[test]
+    fn full_mempool_evicts_lowest_fee() {
+        let (a, b, c) = (key_pair::random(), key_pair::random(), key_pair::random());
+        let mut mempool = Mempool::with_limits(2, DEFAULT_MAX_BYTES);
+        let cheap = mempool.insert(signed(&a, 1, 1), 0).unwrap();
+        mempool.insert(signed(&b, 1, 3), 0).unwrap();
+        assert_eq!(mempool.insert(signed(&c, 1, 1), 0), Err(MempoolError::Full));
+        mempool.insert(signed(&c, 1, 2), 0).unwrap();
+        assert!(!mempool.contains(&cheap));
+        assert_eq!(mempool.len(), 2);
+    }
+
+    #[test]
+    fn selection_by_fee_rate_keeps_nonce_order() {
+        let (a, b) = (key_pair::random(), key_pair::random());
+        let mut mempool = Mempool::new();
+        let a1 = mempool.insert(signed(&a, 1, 1), 0).unwrap();
+        let a2 = mempool.insert(signed(&a, 2, 10), 0).unwrap();
+        let b1 = mempool.insert(signed(&b, 1, 5), 0).unwrap();
+        let state = funded(&[&a, &b], 100);
+        let picked = |max_count, max_bytes| -> Vec<H256> {
+            mempool
+                .ready_transactions(&state, max_count, max_bytes)
+                .iter()
+                .map(|t| t.txid())
+                .collect()
+        };
+        assert_eq!(picked(10, usize::MAX), vec![b1, a1, a2]);
+        assert_eq!(picked(2, usize::MAX), vec![b1, a1]);
+        let size = bincode::serialize(mempool.get(&b1).unwrap()).unwrap().len();
+        assert_eq!(picked(10, size), vec![b1]);
+    }
+
+    #[test]
+    fn selection_stops_at_the_balance_and_the_last_nonce() {
+        let key = key_pair::random();
+        let mut mempool = Mempool::new();
+        // each costs 1 + 2, only two of them fit a balance of 7
+        for nonce in 1..4 {
+            mempool.insert(signed(&key, nonce, 2), 0).unwrap();
+        }
+        let picked = mempool.ready_transactions(&funded(&[&key], 7), 10, usize::MAX);
+        let nonces: Vec<u8> = picked.iter().map(|t| t.tx.account_nonce).collect();
+        assert_eq!(nonces, vec![1, 2]);
+
+        let sender = signed(&key, 0, 0).sender();
+        mempool.insert(signed(&key, 255, 1), 254).unwrap();
+        assert_eq!(mempool.next_nonce(&sender, 254), None);
+        mempool.update_nonce(&sender, 255);
+        assert!(mempool.is_empty());
+        assert_eq!(mempool.next_nonce(&sender, 255), None);
+    }
+
+    #[test]
+    fn saved_transactions_are_revalidated_on_restore() {
+        let key = key_pair::random();
+        let stranger = key_pair::random();
+        let mut mempool = Mempool::new();
+        mempool.insert(signed(&key, 1, 1), 0).unwrap();
+        mempool.insert(signed(&key, 2, 1), 0).unwrap();
+        mempool.insert(signed(&stranger, 1, 1), 0).unwrap();
+        let mut forged = signed(&key, 3, 1);
+        forged.signature = vec![0; 64];
+        mempool.insert(forged, 0).unwrap();
+        let path = std::env::temp_dir().join(format!("mempool-test-{}.dat", std::process::id()));
+        save_transactions(&mempool.transactions(), &path).unwrap();
+        let saved = load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(saved.len(), 4);
+
+        // only the signed payments of an account known to the state come back
+        let state = funded(&[&key], 100);
+        let mut restored = Mempool::new();
+        assert_eq!(restored.restore(saved, &state), 2);
+        let nonces: Vec<u8> = restored
+            .ready_transactions(&state, 10, usize::MAX)
+            .iter()
+            .map(|t| t.tx.account_nonce)
+            .collect();
+        assert_eq!(nonces, vec![1, 2]);
+    }
+}
@@ -0,0 +1,232 @@
+use super::{accept_block, block_template, Handle as MinerHandle};
+use crate::block::{Block, Header};
+use crate::blockchain::Blockchain;
+use crate::crypto::hash::{H160, H256, Hashable};
+use crate::mempool::Mempool;
+use crate::network::inventory::Inventory;
+use crate::network::server::Handle as ServerHandle;
+use log::info;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+/// Number of handed out templates that are remembered for submissions
+const MAX_TEMPLATES: usize = 256;
+
+/// A header template for an external miner.
+///
+/// The miner has to find a `nonce` such that the SHA256 of `header`, with the nonce written in
+/// little endian at `nonce_offset`, is not larger than `difficulty`, then submit it with `id`.
+#[derive(Serialize, Clone, Debug)]
+pub struct Work {
+    pub id: u32,
+    pub parent: String,
+    pub merkle_root: String,
+    /// Address credited with the block reward
+    pub coinbase: String,
+    pub difficulty: String,
+    pub timestamp: u128,
+    pub extra_nonce: u32,
+    /// Hex of the serialized header with a zero nonce
+    pub header: String,
+    pub nonce_offset: usize,
+}
+
+impl Work {
+    fn new(id: u32, header: &Header) -> Self {
+        Work {
+            id,
+            parent: header.parent.to_string(),
+            merkle_root: header.merkle_root.to_string(),
+            coinbase: header.coinbase.to_string(),
+            difficulty: header.difficulty.to_string(),
+            timestamp: header.timestamp,
+            extra_nonce: header.extra_nonce,
+            header: hex::encode(bincode::serialize(header).unwrap()),
+            nonce_offset: bincode::serialized_size(&header.parent).unwrap() as usize,
+        }
+    }
+}
+
+/// Reasons for rejecting a submitted solution.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum SubmitError {
+    /// The id does not belong to a template we handed out recently.
+    UnknownWork,
+    /// The template does not build on the current tip anymore.
+    Stale,
+    /// The header hash is above the difficulty.
+    InvalidProof,
+    /// The transactions of the template no longer apply to the state.
+    InvalidBlock,
+}
+
+impl std::fmt::Display for SubmitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            SubmitError::UnknownWork => write!(f, "unknown work id"),
+            SubmitError::Stale => write!(f, "stale work"),
+            SubmitError::InvalidProof => write!(f, "hash above difficulty"),
+            SubmitError::InvalidBlock => write!(f, "transactions overspend an account"),
+        }
+    }
+}
+
+struct Templates {
+    next_id: u32,
+    issued: VecDeque<(u32, Block)>,
+}
+
+/// Hands out block templates to miners running outside of the node and takes their solutions.
+#[derive(Clone)]
+pub struct Getwork {
+    server: ServerHandle,
+    blockchain: Arc<Mutex<Blockchain>>,
+    mempool: Arc<Mutex<Mempool>>,
+    miner: MinerHandle,
+    /// Rewarded by templates asked for without an address
+    coinbase: H160,
+    templates: Arc<Mutex<Templates>>,
+}
+
+pub fn new(
+    server: &ServerHandle,
+    blockchain: &Arc<Mutex<Blockchain>>,
+    mempool: &Arc<Mutex<Mempool>>,
+    miner: &MinerHandle,
+    coinbase: H160,
+) -> Getwork {
+    Getwork {
+        server: server.clone(),
+        blockchain: Arc::clone(blockchain),
+        mempool: Arc::clone(mempool),
+        miner: miner.clone(),
+        coinbase,
+        templates: Arc::new(Mutex::new(Templates {
+            next_id: 0,
+            issued: VecDeque::new(),
+        })),
+    }
+}
+
+impl Getwork {
+    /// A fresh template on top of the current tip, rewarding the address of the node.
+    pub fn work(&self) -> Work {
+        self.work_to(self.coinbase)
+    }
+
+    /// A fresh template on top of the current tip, rewarding `coinbase`.
+    pub fn work_to(&self, coinbase: H160) -> Work {
+        self.template(coinbase).0
+    }
+
+    /// Like `work_to`, also returning the header of the template, which `header` forgets once
+    /// enough newer templates were handed out.
+    pub fn template(&self, coinbase: H160) -> (Work, Header) {
+        let blc = self.blockchain.lock().unwrap();
+        let mp = self.mempool.lock().unwrap();
+        let mut block = block_template(&blc, &mp);
+        drop(mp);
+        drop(blc);
+        block.header.coinbase = coinbase;
+
+        let mut templates = self.templates.lock().unwrap();
+        let id = templates.next_id;
+        templates.next_id = id.wrapping_add(1);
+        // every template gets its own extra nonce, so no two external miners search the same headers
+        block.header.extra_nonce = id;
+        let work = Work::new(id, &block.header);
+        let header = block.header.clone();
+        templates.issued.push_back((id, block));
+        if templates.issued.len() > MAX_TEMPLATES {
+            templates.issued.pop_front();
+        }
+        (work, header)
+    }
+
+    /// The header of a template handed out recently, with a zero nonce.
+    pub fn header(&self, id: u32) -> Option<Header> {
+        let templates = self.templates.lock().unwrap();
+        templates
+            .issued
+            .iter()
+            .find(|(issued_id, _)| *issued_id == id)
+            .map(|(_, block)| block.header.clone())
+    }
+
+    /// Check a solution for the template `id`. A valid block is inserted and broadcast exactly like
+    /// the ones the internal miner finds. Returns the hash of the new block.
+    pub fn submit(&self, id: u32, nonce: u32) -> Result<H256, SubmitError> {
+        let templates = self.templates.lock().unwrap();
+        let mut block = templates
+            .issued
+            .iter()
+            .find(|(issued_id, _)| *issued_id == id)
+            .map(|(_, block)| block.clone())
+            .ok_or(SubmitError::UnknownWork)?;
+        drop(templates);
+
+        block.header.nonce = nonce;
+        let hash = block.hash();
+        if hash > block.header.difficulty {
+            return Err(SubmitError::InvalidProof);
+        }
+        let mut blc = self.blockchain.lock().unwrap();
+        if blc.tip() != block.header.parent || blc.blocks.contains_key(&hash) {
+            return Err(SubmitError::Stale);
+        }
+        let mut mp = self.mempool.lock().unwrap();
+        let accepted = accept_block(&block, &mut blc, &mut mp);
+        drop(mp);
+        drop(blc);
+        if !accepted {
+            return Err(SubmitError::InvalidBlock);
+        }
+
+        info!("Accepted block {} from an external miner", hash);
+        self.server.announce(Inventory::Blocks(vec![hash]));
+        // the internal miner is now working on an outdated template
+        self.miner.new_tip();
+        Ok(hash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Solve the work the way an external tool would, from the serialized header only.
+    fn solve(work: &Work) -> u32 {
+        let mut header = hex::decode(&work.header).unwrap();
+        let difficulty = hex::decode(&work.difficulty).unwrap();
+        for nonce in 0..=u32::MAX {
+            header[work.nonce_offset..work.nonce_offset + 4].copy_from_slice(&nonce.to_le_bytes());
+            let hash = ring::digest::digest(&ring::digest::SHA256, &header);
+            if hash.as_ref() <= &difficulty[..] {
+                return nonce;
+            }
+        }
+        panic!("no solution for work {}", work.id);
+    }
+
+    #[test]
+    fn external_solution_is_inserted() {
+        let mut params = crate::params::ChainParams::main();
+        params.block_reward = 50;
+        let node = super::super::test_miner(params, 1);
+        let blockchain = node.blockchain;
+        let getwork = new(&node.server, &blockchain, &node.mempool, &node.handle, Default::default());
+
+        let coinbase: H160 = [9; 20].into();
+        let work = getwork.work_to(coinbase);
+        let nonce = solve(&work);
+        assert!(getwork.submit(work.id, nonce.wrapping_add(1)).is_err());
+        let hash = getwork.submit(work.id, nonce).unwrap();
+        let blc = blockchain.lock().unwrap();
+        assert_eq!(blc.tip(), hash);
+        assert_eq!(blc.state()[&coinbase], (0, blc.params.block_reward));
+        drop(blc);
+        assert_eq!(getwork.submit(work.id, nonce), Err(SubmitError::Stale));
+        assert_eq!(getwork.submit(work.id + 1, nonce), Err(SubmitError::UnknownWork));
+    }
+}
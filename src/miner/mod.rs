@@ -0,0 +1,821 @@
+pub mod getwork;
+pub mod pool;
+pub mod strategy;
+
+use crate::network::server::Handle as ServerHandle;
+use crate::network::inventory::Inventory;
+use log::{debug, info, warn};
+use crossbeam::channel::{select, unbounded, Receiver, Sender, TryRecvError};
+use serde::Serialize;
+use std::collections::{HashSet, VecDeque};
+use std::ops::RangeInclusive;
+use std::time;
+use std::thread;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use crate::blockchain::*;
+use crate::block::*;
+use crate::mempool::Mempool;
+use crate::transaction::{verify, SignedTransaction};
+use crate::crypto::hash::{H160, H256, Hashable};
+use crate::crypto::merkle::*;
+use strategy::Strategy;
+
+/// Number of nonces tried between two checks for cancellation, a power of two
+const NONCE_BATCH: u32 = 4096;
+/// Our blocks buried deeper than this under the tip are no longer checked for being orphaned
+const ORPHAN_CHECK_DEPTH: u32 = 100;
+
+enum ControlSignal {
+    Start(u64), // the number controls the lambda of interval between block generation
+    Pause,
+    Resume,
+    MineBlocks(usize, H160, Sender<Vec<H256>>),
+    Exit,
+    NewTip,
+    NewTransactions,
+}
+
+/// Mining statistics, readable through `Handle::stats`
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct Statistics {
+    /// Hashes per second while working on the last template
+    pub hash_rate: f64,
+    /// Total number of nonces tried
+    pub attempts: u64,
+    pub blocks_found: u64,
+    /// Blocks found by this miner that are no longer on the longest chain
+    pub blocks_orphaned: u64,
+    /// Blocks found by this miner that the strategy has not announced yet
+    pub blocks_withheld: u64,
+    /// Number of templates worked on
+    pub templates: u64,
+    pub last_template_millis: u64,
+    pub average_template_millis: f64,
+}
+
+/// A request to mine an exact number of blocks, answered with their hashes
+struct BlockBatch {
+    remaining: usize,
+    coinbase: H160,
+    mined: Vec<H256>,
+    reply: Sender<Vec<H256>>,
+}
+
+/// Outcome of searching the nonce space of one header
+enum Solve {
+    Found,
+    /// A control signal arrived before a solution was found
+    Interrupted,
+    /// No nonce satisfies the difficulty
+    Exhausted,
+}
+
+enum OperatingState {
+    Paused,
+    Run(u64),
+    ShutDown,
+}
+
+pub struct Context {
+    /// Channel for receiving control signal
+    control_chan: Receiver<ControlSignal>,
+    operating_state: OperatingState,
+    /// Lambda of the last continuous run, used by `Resume`
+    lambda: Option<u64>,
+    /// Pending `MineBlocks` requests, served in order whether or not the miner is paused
+    batches: VecDeque<BlockBatch>,
+    server: ServerHandle,
+    blockchain: Arc<Mutex<Blockchain>>,
+    mempool: Arc<Mutex<Mempool>>,
+    /// Whether to mine blocks without transactions when the mempool has nothing ready
+    mine_empty: bool,
+    /// Address credited with the reward of continuously mined blocks
+    coinbase: H160,
+    /// Number of threads searching the nonce space
+    threads: usize,
+    stats: Arc<Mutex<Statistics>>,
+    /// Nonces tried on the current template
+    template_attempts: u64,
+    /// Our blocks still close enough to the tip to become orphans, with their heights
+    recent_blocks: Vec<(H256, u32)>,
+    /// Our blocks that ended up off the longest chain and are now too deep to come back
+    settled_orphans: u64,
+    /// Where to mine and when to announce what we mined
+    strategy: Box<dyn Strategy>,
+}
+
+#[derive(Clone)]
+pub struct Handle {
+    /// Channel for sending signal to the miner thread
+    control_chan: Sender<ControlSignal>,
+    stats: Arc<Mutex<Statistics>>,
+}
+
+pub fn new(
+    server: &ServerHandle,
+    blockchain: &Arc<Mutex<Blockchain>>,
+    mempool: &Arc<Mutex<Mempool>>,
+    mine_empty: bool,
+    coinbase: H160,
+    threads: usize,
+    strategy: Box<dyn Strategy>,
+) -> (Context, Handle) {
+    let (signal_chan_sender, signal_chan_receiver) = unbounded();
+    let stats = Arc::new(Mutex::new(Statistics::default()));
+
+    let ctx = Context {
+        control_chan: signal_chan_receiver,
+        operating_state: OperatingState::Paused,
+        lambda: None,
+        batches: VecDeque::new(),
+        server: server.clone(),
+        blockchain: Arc::clone(blockchain),
+        mempool: Arc::clone(mempool),
+        mine_empty,
+        coinbase,
+        threads,
+        stats: Arc::clone(&stats),
+        template_attempts: 0,
+        recent_blocks: Vec::new(),
+        settled_orphans: 0,
+        strategy,
+    };
+
+    let handle = Handle {
+        control_chan: signal_chan_sender,
+        stats,
+    };
+
+    (ctx, handle)
+}
+
+impl Handle {
+    pub fn exit(&self) {
+        self.control_chan.send(ControlSignal::Exit).unwrap();
+    }
+
+    pub fn start(&self, lambda: u64) {
+        self.control_chan
+            .send(ControlSignal::Start(lambda))
+            .unwrap();
+    }
+
+    /// Stop continuous mining. Blocks requested through `mine_blocks` are still mined.
+    pub fn pause(&self) {
+        self.control_chan.send(ControlSignal::Pause).unwrap();
+    }
+
+    /// Continue continuous mining with the lambda it was last started with.
+    pub fn resume(&self) {
+        self.control_chan.send(ControlSignal::Resume).unwrap();
+    }
+
+    /// Mine exactly `n` blocks as fast as possible, rewarding `coinbase`, and return their hashes
+    /// once all of them are in the blockchain. Afterwards the miner goes back to what it was doing.
+    /// Returns early with the blocks mined so far if the miner shuts down.
+    pub fn mine_blocks(&self, n: usize, coinbase: H160) -> Vec<H256> {
+        let (sender, receiver) = unbounded();
+        self.control_chan
+            .send(ControlSignal::MineBlocks(n, coinbase, sender))
+            .unwrap();
+        receiver.recv().unwrap_or_default()
+    }
+
+    /// A snapshot of the mining statistics.
+    pub fn stats(&self) -> Statistics {
+        self.stats.lock().unwrap().clone()
+    }
+
+    /// Tell the miner the longest chain has a new tip, so the block it is working on is outdated.
+    pub fn new_tip(&self) {
+        // nothing to tell once the miner has exited
+        if self.control_chan.send(ControlSignal::NewTip).is_err() {
+            debug!("Miner is gone, not notifying it of the new tip");
+        }
+    }
+
+    /// Tell the miner new transactions are ready in the mempool.
+    pub fn new_transactions(&self) {
+        if self.control_chan.send(ControlSignal::NewTransactions).is_err() {
+            debug!("Miner is gone, not notifying it of new transactions");
+        }
+    }
+
+}
+
+/// The Merkle root of the block content. The leaves are the `wtxid`s, so the header commits to
+/// the signatures as well. A block without transactions has an all-zero root.
+pub fn merkle_root(data: &[SignedTransaction]) -> H256 {
+    if data.is_empty() {
+        [0; 32].into()
+    } else {
+        let leaves: Vec<H256> = data.iter().map(SignedTransaction::wtxid).collect();
+        MerkleTree::new(&leaves).root()
+    }
+}
+
+/// Assemble a block on top of the current tip with the ready transactions of the mempool.
+/// The nonce is left to the caller.
+pub fn block_template(blockchain: &Blockchain, mempool: &Mempool) -> Block {
+    // return the final block hash in the longest chain
+    block_template_on(blockchain.tip(), blockchain, mempool)
+}
+
+/// Like `block_template`, on top of `parent` instead of the tip.
+pub fn block_template_on(parent: H256, blockchain: &Blockchain, mempool: &Mempool) -> Block {
+    let difficulty = blockchain.blocks.get(&parent).expect("failed").header.difficulty;
+    let params = &blockchain.params;
+    // transactions fill whatever the header leaves of the size limit
+    let empty = Block::new(Header::new(parent, 0, difficulty, now(), [0; 32].into()), Vec::new());
+    let max_bytes = params.max_block_bytes.saturating_sub(empty.size());
+    // only ready transactions, each sender's in nonce order
+    let state = blockchain.state_at(&parent).expect("failed");
+    let data = mempool.ready_transactions(state, params.max_block_txs, max_bytes);
+    let merkle_root = merkle_root(&data);
+    let mut block = empty;
+    block.header.merkle_root = merkle_root;
+    block.data = data;
+    block
+}
+
+/// The accounts changed by `block`: its transactions executed on `state` and its reward paid.
+/// `None` if a transaction has a bad signature, does not use the next nonce of its sender, spends
+/// more than its sender has or a balance overflows.
+pub fn execute_block(block: &Block, state: &State, reward: u32) -> Option<State> {
+    let mut changes = State::new();
+    for signedtx in &block.data {
+        if !verify(&signedtx.tx, signedtx.public_key.clone(), signedtx.signature.clone()) {
+            return None;
+        }
+        let account = signedtx.sender();
+        let (nonce, balance) = *changes.get(&account).or_else(|| state.get(&account))?;
+        if signedtx.tx.account_nonce != nonce.checked_add(1)? {
+            return None;
+        }
+        let balance = balance.checked_sub(signedtx.tx.cost()?)?;
+        changes.insert(account, (signedtx.tx.account_nonce, balance));
+    }
+    if reward > 0 {
+        let coinbase = block.header.coinbase;
+        let (nonce, balance) = changes
+            .get(&coinbase)
+            .or_else(|| state.get(&coinbase))
+            .copied()
+            .unwrap_or((0, 0));
+        changes.insert(coinbase, (nonce, balance.checked_add(reward)?));
+    }
+    Some(changes)
+}
+
+/// Insert a block whose parent we have, mined here or received: execute its transactions on the
+/// parent's state, pay the block reward and add the block to the blockchain. If the tip moves, the
+/// mempool follows it. Broadcasting is left to the caller. Returns `false` without changing
+/// anything if a transaction is invalid on the parent's state.
+#[must_use]
+pub fn accept_block(block: &Block, blockchain: &mut Blockchain, mempool: &mut Mempool) -> bool {
+    let parent_state = blockchain.state_at(&block.header.parent).expect("failed");
+    let changes = match execute_block(block, parent_state, blockchain.params.block_reward) {
+        Some(changes) => changes,
+        None => return false,
+    };
+    let mut state = parent_state.clone();
+    state.extend(changes);
+    let old_tip = blockchain.tip();
+    // insert the block into blockchain
+    blockchain.insert(block, state);
+    if blockchain.tip() != old_tip {
+        follow_tip(old_tip, blockchain, mempool);
+    }
+    true
+}
+
+/// Bring the mempool from the state of `old_tip` to the state of the current tip: transactions
+/// of blocks that left the longest chain go back in, and those now on chain are dropped.
+fn follow_tip(old_tip: H256, blockchain: &Blockchain, mempool: &mut Mempool) {
+    let tip = blockchain.tip();
+    let state = blockchain.state();
+    for hash in blockchain.abandoned(old_tip, tip) {
+        for tx in &blockchain.blocks[&hash].data {
+            if let Some((nonce, _)) = state.get(&tx.sender()) {
+                // already on the new chain or conflicting with it
+                let _ = mempool.insert(tx.clone(), *nonce);
+            }
+        }
+    }
+    let old_state = blockchain.state_at(&old_tip).expect("failed");
+    for (account, (nonce, balance)) in state {
+        if old_state.get(account) != Some(&(*nonce, *balance)) {
+            // delete the included txs from the mempool, and promote the sender's waiting ones
+            mempool.update_nonce(account, *nonce);
+        }
+    }
+}
+
+impl Context {
+    pub fn start(mut self) {
+        thread::Builder::new()
+            .name("miner".to_string())
+            .spawn(move || {
+                self.miner_loop();
+            })
+            .unwrap();
+        info!("Miner initialized into paused mode");
+    }
+
+    fn handle_control_signal(&mut self, signal: ControlSignal) {
+        match signal {
+            ControlSignal::Exit => {
+                info!("Miner shutting down");
+                self.operating_state = OperatingState::ShutDown;
+                for batch in self.batches.drain(..) {
+                    let _ = batch.reply.send(batch.mined);
+                }
+            }
+            ControlSignal::Start(i) => {
+                info!("Miner starting in continuous mode with lambda {}", i);
+                self.operating_state = OperatingState::Run(i);
+                self.lambda = Some(i);
+            }
+            ControlSignal::Pause => {
+                info!("Miner paused");
+                if let OperatingState::Run(_) = self.operating_state {
+                    self.operating_state = OperatingState::Paused;
+                }
+            }
+            ControlSignal::Resume => match (&self.operating_state, self.lambda) {
+                (OperatingState::Paused, Some(i)) => {
+                    info!("Miner resuming with lambda {}", i);
+                    self.operating_state = OperatingState::Run(i);
+                }
+                (OperatingState::Paused, None) => warn!("Miner was never started, cannot resume"),
+                _ => {}
+            },
+            ControlSignal::MineBlocks(n, coinbase, reply) => {
+                info!("Miner asked to mine {} block(s) to {}", n, coinbase);
+                if n == 0 {
+                    let _ = reply.send(Vec::new());
+                } else {
+                    self.batches.push_back(BlockBatch {
+                        remaining: n,
+                        coinbase,
+                        mined: Vec::new(),
+                        reply,
+                    });
+                }
+            }
+            // the loop rebuilds the template after any signal
+            ControlSignal::NewTip => {
+                debug!("Miner notified of a new tip");
+                let mut blc = self.blockchain.lock().unwrap();
+                let announce = self.strategy.on_new_tip(&mut blc);
+                drop(blc);
+                self.announce(announce);
+            }
+            ControlSignal::NewTransactions => {
+                debug!("Miner notified of new transactions");
+            }
+        }
+    }
+
+    /// Block until a control signal arrives and handle it.
+    fn wait_for_signal(&mut self) {
+        let signal = self.control_chan.recv().unwrap();
+        self.handle_control_signal(signal);
+    }
+
+    fn miner_loop(&mut self) {
+        // main mining loop
+        let mut block_num = 0;
+        loop {
+            // check and react to control signals
+            match self.operating_state {
+                OperatingState::Paused if self.batches.is_empty() => {
+                    self.wait_for_signal();
+                    continue;
+                }
+                OperatingState::ShutDown => {
+                    return;
+                }
+                _ => match self.control_chan.try_recv() {
+                    Ok(signal) => {
+                        self.handle_control_signal(signal);
+                    }
+                    Err(TryRecvError::Empty) => {}
+                    Err(TryRecvError::Disconnected) => panic!("Miner control channel detached"),
+                },
+            }
+            if let OperatingState::ShutDown = self.operating_state {
+                return;
+            }
+
+            // the template is built once, only the nonce changes while hashing
+            let mut block = match self.build_template() {
+                Some(block) => block,
+                None => {
+                    // nothing to mine, sleep until a new tip or new transactions show up
+                    self.wait_for_signal();
+                    continue;
+                }
+            };
+            let started = time::Instant::now();
+            let solved = match self.solve(&mut block) {
+                Solve::Found => true,
+                // the template is outdated, or the miner got paused or stopped
+                Solve::Interrupted => false,
+                // every nonce failed on this header, move on to the next extra nonce
+                Solve::Exhausted => self.roll_until_solved(&mut block),
+            };
+            self.record_template(started.elapsed());
+            if !solved {
+                continue;
+            }
+            let hash = block.hash();
+
+            let blockchain = Arc::clone(&self.blockchain);
+            let mut blc = blockchain.lock().unwrap();
+            let mut mp = self.mempool.lock().unwrap();
+            let accepted = accept_block(&block, &mut blc, &mut mp);
+            drop(mp);
+            if !accepted {
+                warn!("Mined block {} has an invalid transaction, dropping it", hash);
+                continue;
+            }
+            self.recent_blocks.push((hash, blc.heights[&hash]));
+            self.stats.lock().unwrap().blocks_found += 1;
+            // change the blocknum
+            block_num += 1;
+            // broadcast the new block hashes to peer, when the strategy wants to
+            let announce = self.strategy.on_mined(hash, &mut blc);
+            self.announce(announce);
+            // print the length of the block
+            let block_size: Vec<u8> = bincode::serialize(&block).unwrap();
+            info!("Current block size is {}", &block_size.len());
+            // print the timestamp and number of blocks mined
+            info!("Successfully mine {} block(s)", &block_num);
+            info!("Timestamp:{}", &block.header.timestamp);
+            for tx in &block.data{
+                let trans = &tx.tx;
+                info!("receiver:{},value:{},account_nonce:{}",trans.recipient_address,trans.value,trans.account_nonce);
+            }
+            for (ac,(n,b)) in blc.state(){
+                info!("Account:{},nonce:{},balance:{}", ac,n,b);
+            }
+            let tip = blc.tip();
+            let num_in_blc = blc.heights.get(&tip).expect("failed");
+            info!("We have {} blocks in our blockchain(m)", &num_in_blc);
+            drop(blc);
+
+            if let Some(batch) = self.batches.front_mut() {
+                batch.mined.push(hash);
+                batch.remaining -= 1;
+                if batch.remaining == 0 {
+                    let batch = self.batches.pop_front().unwrap();
+                    // the requester may have given up waiting
+                    let _ = batch.reply.send(batch.mined);
+                }
+                // requested blocks are mined back to back
+                continue;
+            }
+            if let OperatingState::Run(i) = self.operating_state {
+                if i != 0 {
+                    let interval = time::Duration::from_micros(i);
+                    thread::sleep(interval);
+                }
+            }
+        }
+    }
+
+    /// Broadcast our blocks the strategy decided to make public.
+    fn announce(&mut self, blocks: Vec<H256>) {
+        self.stats.lock().unwrap().blocks_withheld = self.strategy.withheld() as u64;
+        if blocks.is_empty() {
+            return;
+        }
+        self.server.announce(Inventory::Blocks(blocks));
+    }
+
+    /// Assemble a block on top of the block chosen by the strategy with the ready transactions of
+    /// the mempool. Returns `None` if there is nothing to include and empty blocks are not wanted.
+    fn build_template(&mut self) -> Option<Block> {
+        let blockchain = Arc::clone(&self.blockchain);
+        let blc = blockchain.lock().unwrap();
+        let parent = self.strategy.parent(&blc);
+        // a new template usually means a new tip, which may have orphaned our blocks
+        self.update_orphans(&blc, parent);
+        let mp = self.mempool.lock().unwrap();
+        let mut block = block_template_on(parent, &blc, &mp);
+        drop(mp);
+        drop(blc);
+        block.header.coinbase = match self.batches.front() {
+            Some(batch) => batch.coinbase,
+            None => self.coinbase,
+        };
+        // requested blocks have to be mined even without transactions
+        if block.data.is_empty() && !self.mine_empty && self.batches.is_empty() {
+            return None;
+        }
+        Some(block)
+    }
+
+    /// Fold the work done on the last template into the statistics.
+    fn record_template(&mut self, elapsed: time::Duration) {
+        let attempts = std::mem::take(&mut self.template_attempts);
+        let mut stats = self.stats.lock().unwrap();
+        let millis = elapsed.as_millis() as u64;
+        stats.attempts += attempts;
+        if elapsed.as_secs_f64() > 0.0 {
+            stats.hash_rate = attempts as f64 / elapsed.as_secs_f64();
+        }
+        stats.average_template_millis = (stats.average_template_millis * stats.templates as f64
+            + millis as f64)
+            / (stats.templates + 1) as f64;
+        stats.templates += 1;
+        stats.last_template_millis = millis;
+    }
+
+    /// Recount our blocks that are not on the chain ending in `tip` anymore, the one we mine on.
+    fn update_orphans(&mut self, blc: &Blockchain, tip: H256) {
+        let tip_height = blc.heights[&tip];
+        let lowest = tip_height.saturating_sub(ORPHAN_CHECK_DEPTH);
+        // the recent part of the longest chain
+        let mut on_chain = HashSet::new();
+        let mut hash = tip;
+        loop {
+            on_chain.insert(hash);
+            if blc.heights[&hash] <= lowest {
+                break;
+            }
+            hash = blc.blocks[&hash].header.parent;
+        }
+        let mut orphaned = 0;
+        let settled_orphans = &mut self.settled_orphans;
+        self.recent_blocks.retain(|(hash, height)| {
+            let orphan = !on_chain.contains(hash);
+            if *height < lowest {
+                // too deep to ever be part of the longest chain again
+                if orphan {
+                    *settled_orphans += 1;
+                }
+                return false;
+            }
+            if orphan {
+                orphaned += 1;
+            }
+            true
+        });
+        self.stats.lock().unwrap().blocks_orphaned = self.settled_orphans + orphaned;
+    }
+
+    /// Keep the content of the template but search fresh headers: bump the extra nonce and refresh
+    /// the timestamp every time the nonce space runs out. Returns `false` if interrupted.
+    fn roll_until_solved(&mut self, block: &mut Block) -> bool {
+        loop {
+            block.header.extra_nonce = match block.header.extra_nonce.checked_add(1) {
+                Some(extra_nonce) => extra_nonce,
+                // the whole 64-bit space is done, the caller builds a new template
+                None => return false,
+            };
+            block.header.timestamp = now();
+            debug!("Nonce space exhausted, rolling extra nonce to {}", block.header.extra_nonce);
+            match self.solve(block) {
+                Solve::Found => return true,
+                Solve::Interrupted => return false,
+                Solve::Exhausted => continue,
+            }
+        }
+    }
+
+    /// Search the nonce space of the template with all mining threads, each on a disjoint range.
+    /// On success the winning nonce is set in the header. All threads have stopped when this returns.
+    fn solve(&mut self, block: &mut Block) -> Solve {
+        let threads = self.threads;
+        let cancel = AtomicBool::new(false);
+        let attempts = AtomicU64::new(0);
+        let (found_sender, found_receiver) = unbounded();
+        let result = crossbeam::scope(|scope| {
+            for range in nonce_ranges(threads) {
+                let header = block.header.clone();
+                let found_sender = found_sender.clone();
+                let cancel = &cancel;
+                let attempts = &attempts;
+                scope.spawn(move |_| {
+                    let found = search(header, range, cancel, attempts);
+                    // the receiver is gone once another thread won
+                    let _ = found_sender.send(found);
+                });
+            }
+            let mut exhausted = 0;
+            let result = loop {
+                select! {
+                    recv(found_receiver) -> found => match found.unwrap() {
+                        Some(nonce) => {
+                            block.header.nonce = nonce;
+                            break Solve::Found;
+                        }
+                        None => {
+                            exhausted += 1;
+                            if exhausted == threads {
+                                break Solve::Exhausted;
+                            }
+                        }
+                    },
+                    recv(self.control_chan) -> signal => {
+                        let signal = signal.expect("Miner control channel detached");
+                        self.handle_control_signal(signal);
+                        break Solve::Interrupted;
+                    },
+                }
+            };
+            // first one to find a solution, or any control signal, stops everyone
+            cancel.store(true, Ordering::Relaxed);
+            result
+        })
+        .unwrap();
+        self.template_attempts += attempts.into_inner();
+        result
+    }
+}
+
+/// Split the nonce space into `threads` disjoint ranges covering all of it.
+fn nonce_ranges(threads: usize) -> Vec<RangeInclusive<u32>> {
+    let total = u64::from(u32::MAX) + 1;
+    let threads = threads as u64;
+    (0..threads)
+        .map(|i| {
+            let start = total * i / threads;
+            let end = total * (i + 1) / threads - 1;
+            start as u32..=end as u32
+        })
+        .collect()
+}
+
+/// Try every nonce of `range` on the header. Gives up as soon as `cancel` is set.
+/// The number of nonces tried is added to `attempts`.
+fn search(
+    mut header: Header,
+    range: RangeInclusive<u32>,
+    cancel: &AtomicBool,
+    attempts: &AtomicU64,
+) -> Option<u32> {
+    let difficulty = header.difficulty;
+    let mut tried = 0;
+    let mut found = None;
+    for nonce in range {
+        // NONCE_BATCH is a power of two
+        if nonce & (NONCE_BATCH - 1) == 0 && cancel.load(Ordering::Relaxed) {
+            break;
+        }
+        header.nonce = nonce;
+        tried += 1;
+        // calculate the hash and compare the difficulty
+        if header.hash() <= difficulty {
+            found = Some(nonce);
+            break;
+        }
+    }
+    attempts.fetch_add(tried, Ordering::Relaxed);
+    found
+}
+
+/// A miner on a server that is never started, for the tests of the miner, getwork and the pool
+#[cfg(test)]
+pub(crate) struct TestMiner {
+    pub blockchain: Arc<Mutex<Blockchain>>,
+    pub mempool: Arc<Mutex<Mempool>>,
+    pub server: ServerHandle,
+    pub ctx: Context,
+    pub handle: Handle,
+    // keep the channels of the server open
+    _server_ctx: crate::network::server::Context,
+    _msg_receiver: Receiver<(crate::network::frame::Frame, crate::network::peer::Handle)>,
+}
+
+#[cfg(test)]
+pub(crate) fn test_miner(params: crate::params::ChainParams, threads: usize) -> TestMiner {
+    let (msg_sender, msg_receiver) = unbounded();
+    let frame_config = crate::network::frame::Config::new(&params);
+    let blockchain = Arc::new(Mutex::new(Blockchain::with_params(params)));
+    let addr = "127.0.0.1:0".parse().unwrap();
+    let local = crate::network::handshake::Local::new(&blockchain, addr);
+    let (server_ctx, server) =
+        crate::network::server::new(addr, msg_sender, frame_config, None, local, &Default::default())
+            .unwrap();
+    let mempool = Arc::new(Mutex::new(Mempool::new()));
+    let (ctx, handle) = new(
+        &server,
+        &blockchain,
+        &mempool,
+        false,
+        H160::default(),
+        threads,
+        Box::new(strategy::Honest),
+    );
+    TestMiner {
+        blockchain,
+        mempool,
+        server,
+        ctx,
+        handle,
+        _server_ctx: server_ctx,
+        _msg_receiver: msg_receiver,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nonce_ranges_cover_everything_once() {
+        let ranges = nonce_ranges(3);
+        assert_eq!(*ranges[0].start(), 0);
+        assert_eq!(*ranges[2].end(), u32::MAX);
+        for pair in ranges.windows(2) {
+            assert_eq!(*pair[0].end() + 1, *pair[1].start());
+        }
+        assert_eq!(nonce_ranges(1), vec![0..=u32::MAX]);
+    }
+
+    #[test]
+    fn merkle_root_commits_to_signatures() {
+        use crate::transaction::{sign, Transaction};
+        use ring::signature::KeyPair;
+        let key = crate::crypto::key_pair::random();
+        let tx = Transaction::new([0; 20].into(), 1, 1, 1);
+        let signature = sign(&tx, &key).as_ref().to_vec();
+        let public_key = key.public_key().as_ref().to_vec();
+        let signed = SignedTransaction::new(tx.clone(), signature, public_key.clone());
+        let forged = SignedTransaction::new(tx, vec![0; 64], public_key);
+        assert_eq!(signed.txid(), forged.txid());
+        assert_ne!(merkle_root(&[signed]), merkle_root(&[forged]));
+    }
+
+    #[test]
+    fn blocks_execute_on_their_parent_state() {
+        use crate::crypto::key_pair;
+        use crate::transaction::{sign, Transaction};
+        use ring::signature::KeyPair;
+        let mut blockchain = Blockchain::with_params(crate::params::ChainParams::regtest());
+        let mut mempool = Mempool::new();
+        let genesis = blockchain.tip();
+        let key = key_pair::ico(0);
+        let pay = |nonce| {
+            let tx = Transaction::new([0; 20].into(), 1, nonce, 1);
+            let signature = sign(&tx, &key).as_ref().to_vec();
+            SignedTransaction::new(tx, signature, key.public_key().as_ref().to_vec())
+        };
+        let sender = pay(1).sender();
+        let balance = blockchain.state()[&sender].1;
+        let difficulty = blockchain.params.difficulty;
+        let block_on = |parent, data: Vec<SignedTransaction>, nonce| {
+            let root = merkle_root(&data);
+            Block::new(Header::new(parent, nonce, difficulty, 0, root), data)
+        };
+
+        let a = block_on(genesis, vec![pay(1)], 0);
+        assert!(accept_block(&a, &mut blockchain, &mut mempool));
+        assert_eq!(blockchain.state()[&sender], (1, balance - 2));
+        // a replay, a skipped nonce and a forged signature are refused
+        let replay = block_on(a.hash(), vec![pay(1)], 0);
+        assert!(!accept_block(&replay, &mut blockchain, &mut mempool));
+        let gap = block_on(a.hash(), vec![pay(3)], 0);
+        assert!(!accept_block(&gap, &mut blockchain, &mut mempool));
+        let mut forged = pay(2);
+        forged.signature = vec![0; 64];
+        let forged = block_on(a.hash(), vec![forged], 0);
+        assert!(!accept_block(&forged, &mut blockchain, &mut mempool));
+
+        // a side branch leaves the state of the tip alone
+        let b = block_on(genesis, vec![], 1);
+        assert!(accept_block(&b, &mut blockchain, &mut mempool));
+        assert_eq!(blockchain.tip(), a.hash());
+        assert_eq!(blockchain.state()[&sender], (1, balance - 2));
+        // until it takes over, then the payment of the abandoned block is pending again
+        let c = block_on(b.hash(), vec![], 2);
+        assert!(accept_block(&c, &mut blockchain, &mut mempool));
+        assert_eq!(blockchain.tip(), c.hash());
+        assert_eq!(blockchain.state()[&sender], (0, balance));
+        assert!(mempool.contains(&pay(1).txid()));
+    }
+
+    #[test]
+    fn mine_blocks_returns_exact_count() {
+        let node = test_miner(crate::params::ChainParams::regtest(), 2);
+        let reward = node.blockchain.lock().unwrap().params.block_reward;
+        node.ctx.start();
+        let coinbase: H160 = [7; 20].into();
+        let hashes = node.handle.mine_blocks(2, coinbase);
+        assert_eq!(hashes.len(), 2);
+        let blc = node.blockchain.lock().unwrap();
+        assert_eq!(blc.tip(), hashes[1]);
+        assert_eq!(blc.blocks[&hashes[1]].header.parent, hashes[0]);
+        assert_eq!(blc.state()[&coinbase], (0, 2 * reward));
+        drop(blc);
+        let stats = node.handle.stats();
+        assert_eq!(stats.blocks_found, 2);
+        assert_eq!(stats.blocks_orphaned, 0);
+        assert!(stats.attempts >= 2);
+        node.handle.exit();
+    }
+}
@@ -0,0 +1,303 @@
+use super::getwork::{Getwork, SubmitError, Work};
+use crate::blockchain::Blockchain;
+use crate::crypto::hash::{H160, H256, Hashable};
+use log::info;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+
+/// Number of most recent shares a block reward is split over
+const PPLNS_WINDOW: usize = 1000;
+/// Shares are 2^SHARE_TARGET_SHIFT times easier than blocks, must be between 1 and 7
+const SHARE_TARGET_SHIFT: u32 = 4;
+/// Templates remembered for duplicate detection before pruning the ones getwork forgot
+const MAX_SUBMITTED_TEMPLATES: usize = 256;
+
+/// The target a share has to meet, `difficulty` made 2^SHARE_TARGET_SHIFT times easier.
+pub fn share_target(difficulty: &H256) -> H256 {
+    let bytes: [u8; 32] = difficulty.into();
+    // on overflow every hash is a share
+    if bytes[0].leading_zeros() < SHARE_TARGET_SHIFT {
+        return [0xff; 32].into();
+    }
+    let mut target = [0u8; 32];
+    for (i, byte) in target.iter_mut().enumerate() {
+        let carry = bytes.get(i + 1).map_or(0, |b| b >> (8 - SHARE_TARGET_SHIFT));
+        *byte = bytes[i] << SHARE_TARGET_SHIFT | carry;
+    }
+    target.into()
+}
+
+/// A template for a pool worker. Hashes at or below `share_target` earn a share, hashes at or
+/// below the difficulty of the work also make a block.
+#[derive(Serialize, Clone, Debug)]
+pub struct PoolWork {
+    pub work: Work,
+    pub share_target: String,
+}
+
+/// An accepted share.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Share {
+    Accepted,
+    /// The share also solved a block, which has been inserted and paid out
+    Block(H256),
+}
+
+/// Reasons for rejecting a share.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ShareError {
+    UnknownWork,
+    /// The template does not build on the current tip anymore.
+    Stale,
+    /// The nonce was already submitted for this template.
+    Duplicate,
+    /// The header hash is above the share target.
+    AboveTarget,
+}
+
+impl std::fmt::Display for ShareError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ShareError::UnknownWork => write!(f, "unknown work id"),
+            ShareError::Stale => write!(f, "stale work"),
+            ShareError::Duplicate => write!(f, "duplicate share"),
+            ShareError::AboveTarget => write!(f, "hash above share target"),
+        }
+    }
+}
+
+/// Per worker numbers, readable through `Pool::stats`
+#[derive(Serialize, Clone, Debug)]
+pub struct WorkerStatistics {
+    pub address: String,
+    /// Total number of accepted shares
+    pub shares: u64,
+    /// Shares inside the current PPLNS window
+    pub window_shares: u64,
+    /// Total reward credited
+    pub balance: u64,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Statistics {
+    pub blocks_found: u64,
+    pub window: usize,
+    pub workers: Vec<WorkerStatistics>,
+}
+
+/// Pay per last N shares accounting. Every block reward is split over the last `capacity`
+/// shares, in proportion to how many of them each worker submitted.
+pub struct Ledger {
+    window: VecDeque<H160>,
+    capacity: usize,
+    shares: HashMap<H160, u64>,
+    balances: HashMap<H160, u64>,
+    blocks_found: u64,
+}
+
+impl Ledger {
+    pub fn new(capacity: usize) -> Self {
+        Ledger {
+            window: VecDeque::with_capacity(capacity),
+            capacity,
+            shares: HashMap::new(),
+            balances: HashMap::new(),
+            blocks_found: 0,
+        }
+    }
+
+    pub fn add_share(&mut self, worker: H160) {
+        self.window.push_back(worker);
+        if self.window.len() > self.capacity {
+            self.window.pop_front();
+        }
+        *self.shares.entry(worker).or_insert(0) += 1;
+    }
+
+    /// Split `reward` over the shares in the window. What is lost to rounding goes to `finder`.
+    pub fn pay_block(&mut self, reward: u64, finder: H160) -> HashMap<H160, u64> {
+        let mut payouts: HashMap<H160, u64> = HashMap::new();
+        for worker in &self.window {
+            *payouts.entry(*worker).or_insert(0) += 1;
+        }
+        let total = self.window.len() as u64;
+        let mut paid = 0;
+        for count in payouts.values_mut() {
+            *count = reward * *count / total;
+            paid += *count;
+        }
+        *payouts.entry(finder).or_insert(0) += reward - paid;
+        for (worker, amount) in &payouts {
+            *self.balances.entry(*worker).or_insert(0) += amount;
+        }
+        self.blocks_found += 1;
+        payouts
+    }
+
+    pub fn balance(&self, worker: &H160) -> u64 {
+        self.balances.get(worker).copied().unwrap_or(0)
+    }
+
+    pub fn stats(&self) -> Statistics {
+        let mut window_shares: HashMap<H160, u64> = HashMap::new();
+        for worker in &self.window {
+            *window_shares.entry(*worker).or_insert(0) += 1;
+        }
+        let workers = self
+            .shares
+            .iter()
+            .map(|(worker, shares)| WorkerStatistics {
+                address: worker.to_string(),
+                shares: *shares,
+                window_shares: window_shares.get(worker).copied().unwrap_or(0),
+                balance: self.balance(worker),
+            })
+            .collect();
+        Statistics {
+            blocks_found: self.blocks_found,
+            window: self.window.len(),
+            workers,
+        }
+    }
+}
+
+/// Runs the node as a small pool on top of getwork, crediting shares to worker addresses. The
+/// blocks pay the pool's address, the reward of each is split among the workers.
+#[derive(Clone)]
+pub struct Pool {
+    getwork: Getwork,
+    blockchain: Arc<Mutex<Blockchain>>,
+    /// Address the blocks of the pool pay
+    coinbase: H160,
+    ledger: Arc<Mutex<Ledger>>,
+    /// Nonces submitted for every template, to reject the same share twice
+    submitted: Arc<Mutex<HashMap<u32, HashSet<u32>>>>,
+}
+
+pub fn new(blockchain: &Arc<Mutex<Blockchain>>, getwork: &Getwork, coinbase: H160) -> Pool {
+    Pool {
+        getwork: getwork.clone(),
+        blockchain: Arc::clone(blockchain),
+        coinbase,
+        ledger: Arc::new(Mutex::new(Ledger::new(PPLNS_WINDOW))),
+        submitted: Arc::new(Mutex::new(HashMap::new())),
+    }
+}
+
+impl Pool {
+    pub fn work(&self) -> PoolWork {
+        let (work, header) = self.getwork.template(self.coinbase);
+        PoolWork {
+            work,
+            share_target: share_target(&header.difficulty).to_string(),
+        }
+    }
+
+    pub fn submit(&self, worker: H160, id: u32, nonce: u32) -> Result<Share, ShareError> {
+        let mut header = self.getwork.header(id).ok_or(ShareError::UnknownWork)?;
+        header.nonce = nonce;
+        let hash = header.hash();
+        if hash > share_target(&header.difficulty) {
+            return Err(ShareError::AboveTarget);
+        }
+        if self.blockchain.lock().unwrap().tip() != header.parent {
+            return Err(ShareError::Stale);
+        }
+        let mut submitted = self.submitted.lock().unwrap();
+        if !submitted.entry(id).or_default().insert(nonce) {
+            return Err(ShareError::Duplicate);
+        }
+        if submitted.len() > MAX_SUBMITTED_TEMPLATES {
+            submitted.retain(|id, _| self.getwork.header(*id).is_some());
+        }
+        drop(submitted);
+
+        if hash > header.difficulty {
+            self.ledger.lock().unwrap().add_share(worker);
+            return Ok(Share::Accepted);
+        }
+        let block = self.getwork.submit(id, nonce).map_err(|e| match e {
+            SubmitError::UnknownWork => ShareError::UnknownWork,
+            SubmitError::Stale | SubmitError::InvalidBlock => ShareError::Stale,
+            SubmitError::InvalidProof => ShareError::AboveTarget,
+        })?;
+        let reward = self.blockchain.lock().unwrap().params.block_reward;
+        let mut ledger = self.ledger.lock().unwrap();
+        ledger.add_share(worker);
+        let payouts = ledger.pay_block(u64::from(reward), worker);
+        drop(ledger);
+        info!("Pool found block {}, paid {} workers", block, payouts.len());
+        Ok(Share::Block(block))
+    }
+
+    pub fn stats(&self) -> Statistics {
+        self.ledger.lock().unwrap().stats()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    #[test]
+    fn share_target_is_easier() {
+        let difficulty: H256 = [0, 1, 14, 4, 3, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+            0, 0, 0, 0, 0, 0, 0, 0].into();
+        let expected: H256 = [0, 16, 224, 64, 48, 16, 16, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+            0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0].into();
+        assert_eq!(share_target(&difficulty), expected);
+        assert_eq!(share_target(&[0xff; 32].into()), [0xff; 32].into());
+    }
+
+    /// Workers with different hash rates try random nonces on the work of the pool; over many
+    /// blocks each one should be paid in proportion to its hash rate, and the ledger should
+    /// split exactly what the blocks paid the pool.
+    #[test]
+    fn payouts_follow_hash_rate() {
+        // one hash in four is a share, one in 64 a block
+        let mut params = crate::params::ChainParams::main();
+        let mut difficulty = [0xff; 32];
+        difficulty[0] = 0x03;
+        params.difficulty = difficulty.into();
+        params.block_reward = 50;
+        let node = super::super::test_miner(params, 1);
+        let blockchain = node.blockchain;
+        let getwork = super::super::getwork::new(
+            &node.server,
+            &blockchain,
+            &node.mempool,
+            &node.handle,
+            Default::default(),
+        );
+        let coinbase: H160 = [0xee; 20].into();
+        let pool = new(&blockchain, &getwork, coinbase);
+
+        let rates = [1usize, 2, 3, 4];
+        let workers: Vec<H160> = (0..rates.len() as u8).map(|i| [i; 20].into()).collect();
+        let mut rng = StdRng::seed_from_u64(37);
+        while pool.stats().blocks_found < 1000 {
+            for (worker, rate) in workers.iter().zip(rates.iter()) {
+                let work = pool.work().work;
+                for _ in 0..*rate {
+                    // stale and duplicate shares are simply lost
+                    let _ = pool.submit(*worker, work.id, rng.gen());
+                }
+            }
+        }
+        let blocks = pool.stats().blocks_found;
+        let ledger = pool.ledger.lock().unwrap();
+        let paid: u64 = workers.iter().map(|w| ledger.balance(w)).sum();
+        let (_, earned) = blockchain.lock().unwrap().state()[&coinbase];
+        assert_eq!(paid, blocks * 50);
+        assert_eq!(u64::from(earned), paid);
+        let total_rate: usize = rates.iter().sum();
+        for (worker, rate) in workers.iter().zip(rates.iter()) {
+            let fair = paid as f64 * *rate as f64 / total_rate as f64;
+            let error = (ledger.balance(worker) as f64 - fair).abs() / fair;
+            assert!(error < 0.1, "worker with rate {} is off by {:.3}", rate, error);
+        }
+    }
+}
@@ -0,0 +1,217 @@
+use crate::blockchain::Blockchain;
+use crate::crypto::hash::H256;
+use std::collections::VecDeque;
+
+/// Decides which block the miner builds on and when the blocks it mines become public.
+///
+/// Mined blocks are always inserted into the local blockchain. A strategy keeping some of them
+/// private withholds them with `Blockchain::withhold`, so they are neither served to peers nor
+/// part of the tip until published.
+pub trait Strategy: Send {
+    /// The block the next template is built on.
+    fn parent(&mut self, blockchain: &Blockchain) -> H256;
+    /// We mined `block`, already inserted into `blockchain`. Returns the blocks to announce now.
+    fn on_mined(&mut self, block: H256, blockchain: &mut Blockchain) -> Vec<H256>;
+    /// Blocks from elsewhere changed the chain. Returns the blocks to announce now.
+    fn on_new_tip(&mut self, blockchain: &mut Blockchain) -> Vec<H256>;
+    /// Number of our blocks not announced yet.
+    fn withheld(&self) -> usize {
+        0
+    }
+}
+
+/// Build on the longest chain and announce every block right away.
+pub struct Honest;
+
+impl Strategy for Honest {
+    fn parent(&mut self, blockchain: &Blockchain) -> H256 {
+        blockchain.tip()
+    }
+
+    fn on_mined(&mut self, block: H256, _blockchain: &mut Blockchain) -> Vec<H256> {
+        vec![block]
+    }
+
+    fn on_new_tip(&mut self, _blockchain: &mut Blockchain) -> Vec<H256> {
+        Vec::new()
+    }
+}
+
+/// Selfish mining after Eyal and Sirer: keep mining on a private branch and only reveal blocks
+/// when the public chain threatens to overtake it.
+///
+/// `release_lead` is the lead at or below which an honest block makes us reveal the whole private
+/// branch. 1 is the classic strategy; larger values give up the advantage earlier. With a larger
+/// lead, only enough blocks to match the public chain are revealed.
+pub struct Selfish {
+    release_lead: u32,
+    /// Tip of the branch we mine on, part of it may be public already
+    branch: Option<H256>,
+    /// Blocks of our branch not announced yet, oldest first
+    private: VecDeque<H256>,
+    /// Whether our revealed branch is racing a public block of the same height
+    racing: bool,
+}
+
+impl Selfish {
+    pub fn new(release_lead: u32) -> Self {
+        Selfish {
+            release_lead,
+            branch: None,
+            private: VecDeque::new(),
+            racing: false,
+        }
+    }
+
+    /// The longest chain known to everyone else, preferring our branch on ties.
+    fn public_tip(&self, blockchain: &Blockchain) -> (H256, u32) {
+        let tip = blockchain.tip();
+        let height = blockchain.heights[&tip];
+        match self.branch {
+            Some(branch)
+                if !blockchain.is_withheld(&branch) && blockchain.heights[&branch] == height =>
+            {
+                (branch, height)
+            }
+            _ => (tip, height),
+        }
+    }
+
+    /// Publish private blocks up to `height`, all of them if `None`.
+    fn reveal(&mut self, blockchain: &mut Blockchain, height: Option<u32>) -> Vec<H256> {
+        let mut revealed = Vec::new();
+        while let Some(hash) = self.private.front() {
+            if let Some(height) = height {
+                if blockchain.heights[hash] > height {
+                    break;
+                }
+            }
+            blockchain.publish(*hash);
+            revealed.push(*hash);
+            self.private.pop_front();
+        }
+        revealed
+    }
+}
+
+impl Strategy for Selfish {
+    fn parent(&mut self, blockchain: &Blockchain) -> H256 {
+        match self.branch {
+            Some(branch) => branch,
+            None => blockchain.tip(),
+        }
+    }
+
+    fn on_mined(&mut self, block: H256, blockchain: &mut Blockchain) -> Vec<H256> {
+        self.branch = Some(block);
+        self.private.push_back(block);
+        blockchain.withhold(block);
+        if self.racing {
+            // one block ahead of the competing branch, reveal and win the race
+            self.racing = false;
+            return self.reveal(blockchain, None);
+        }
+        Vec::new()
+    }
+
+    fn on_new_tip(&mut self, blockchain: &mut Blockchain) -> Vec<H256> {
+        let branch = match self.branch {
+            Some(branch) => branch,
+            None => return Vec::new(),
+        };
+        let (public, public_height) = self.public_tip(blockchain);
+        if public == branch {
+            return Vec::new();
+        }
+        let height = blockchain.heights[&branch];
+        if public_height > height {
+            // the public chain is longer, give up and mine on it
+            self.branch = None;
+            // our blocks lost, publishing them changes nothing anymore
+            for hash in self.private.drain(..) {
+                blockchain.publish(hash);
+            }
+            self.racing = false;
+            return Vec::new();
+        }
+        let lead = height - public_height;
+        if lead == 0 {
+            // the public chain caught up, reveal to race it
+            self.racing = true;
+            return self.reveal(blockchain, None);
+        }
+        if lead <= self.release_lead {
+            return self.reveal(blockchain, None);
+        }
+        self.reveal(blockchain, Some(public_height))
+    }
+
+    fn withheld(&self) -> usize {
+        self.private.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::{Block, Header};
+    use crate::crypto::hash::Hashable;
+
+    fn child(blockchain: &mut Blockchain, parent: H256, nonce: u32) -> H256 {
+        let difficulty = blockchain.blocks[&parent].header.difficulty;
+        let header = Header::new(parent, nonce, difficulty, 0, [0; 32].into());
+        let block = Block::new(header, Vec::new());
+        let state = blockchain.state_at(&parent).unwrap().clone();
+        blockchain.insert(&block, state);
+        block.hash()
+    }
+
+    #[test]
+    fn selfish_withholds_until_caught_up() {
+        let mut blockchain = Blockchain::new();
+        let genesis = blockchain.tip();
+        let mut selfish = Selfish::new(1);
+
+        let parent = selfish.parent(&blockchain);
+        let ours1 = child(&mut blockchain, parent, 1);
+        assert!(selfish.on_mined(ours1, &mut blockchain).is_empty());
+        let parent = selfish.parent(&blockchain);
+        let ours2 = child(&mut blockchain, parent, 2);
+        assert!(selfish.on_mined(ours2, &mut blockchain).is_empty());
+        assert_eq!(selfish.withheld(), 2);
+        // peers see neither the private blocks nor their height
+        assert_eq!(blockchain.tip(), genesis);
+        assert!(blockchain.public_block(&ours1).is_none());
+        assert!(blockchain.headers_after(&[genesis], 10).is_empty());
+
+        // an honest block cuts the lead to one, the whole branch goes public
+        child(&mut blockchain, genesis, 3);
+        assert_eq!(selfish.on_new_tip(&mut blockchain), vec![ours1, ours2]);
+        assert_eq!(selfish.parent(&blockchain), ours2);
+        assert_eq!(blockchain.tip(), ours2);
+
+        // a longer public chain wins
+        let theirs = child(&mut blockchain, ours2, 4);
+        let theirs = child(&mut blockchain, theirs, 5);
+        assert!(selfish.on_new_tip(&mut blockchain).is_empty());
+        assert_eq!(selfish.parent(&blockchain), theirs);
+        assert_eq!(selfish.withheld(), 0);
+    }
+
+    #[test]
+    fn selfish_races_on_a_tie() {
+        let mut blockchain = Blockchain::new();
+        let genesis = blockchain.tip();
+        let mut selfish = Selfish::new(1);
+
+        let parent = selfish.parent(&blockchain);
+        let ours1 = child(&mut blockchain, parent, 1);
+        selfish.on_mined(ours1, &mut blockchain);
+        child(&mut blockchain, genesis, 2);
+        assert_eq!(selfish.on_new_tip(&mut blockchain), vec![ours1]);
+        // winning the race reveals the next block immediately
+        let parent = selfish.parent(&blockchain);
+        let ours2 = child(&mut blockchain, parent, 3);
+        assert_eq!(selfish.on_mined(ours2, &mut blockchain), vec![ours2]);
+    }
+}
@@ -0,0 +1,216 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Maximum number of addresses remembered
+const MAX_ADDRESSES: usize = 1000;
+/// Maximum number of addresses in one `Addr` message
+pub const MAX_ADDR_PER_MESSAGE: usize = 100;
+/// Addresses that failed this many times in a row are not passed on to peers
+const MAX_SHARED_FAILURES: u32 = 3;
+/// How often the address book is written to the data directory, in seconds
+pub const SAVE_INTERVAL_SECS: u64 = 60;
+
+/// Seconds since the Unix epoch
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// An address as gossiped in `Addr` messages
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KnownAddr {
+    pub addr: SocketAddr,
+    /// When the address was last known to accept connections, in Unix seconds
+    pub last_seen: u64,
+}
+
+/// Everything we know about one peer address
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AddrInfo {
+    pub addr: SocketAddr,
+    pub last_seen: u64,
+    pub last_attempt: Option<u64>,
+    pub last_success: Option<u64>,
+    /// Failed connection attempts since the last success
+    pub failures: u32,
+}
+
+/// Addresses of peers we know of, either connected to or heard of from other peers.
+#[derive(Default)]
+pub struct AddressBook {
+    entries: HashMap<SocketAddr, AddrInfo>,
+}
+
+impl AddressBook {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn get(&self, addr: &SocketAddr) -> Option<&AddrInfo> {
+        self.entries.get(addr)
+    }
+
+    pub fn addresses(&self) -> Vec<SocketAddr> {
+        self.entries.keys().copied().collect()
+    }
+
+    /// Learn about an address, or that it was seen more recently.
+    pub fn add(&mut self, addr: SocketAddr, last_seen: u64) {
+        // nobody can connect to these
+        if addr.ip().is_unspecified() || addr.port() == 0 {
+            return;
+        }
+        // gossip from the future is clamped
+        let last_seen = last_seen.min(unix_now());
+        if let Some(info) = self.entries.get_mut(&addr) {
+            info.last_seen = info.last_seen.max(last_seen);
+            return;
+        }
+        if self.entries.len() >= MAX_ADDRESSES {
+            self.evict();
+        }
+        self.entries.insert(
+            addr,
+            AddrInfo {
+                addr,
+                last_seen,
+                last_attempt: None,
+                last_success: None,
+                failures: 0,
+            },
+        );
+    }
+
+    pub fn remove(&mut self, addr: &SocketAddr) {
+        self.entries.remove(addr);
+    }
+
+    pub fn mark_attempt(&mut self, addr: SocketAddr) {
+        self.add(addr, 0);
+        if let Some(info) = self.entries.get_mut(&addr) {
+            info.last_attempt = Some(unix_now());
+        }
+    }
+
+    /// A connection to `addr` completed the handshake.
+    pub fn mark_success(&mut self, addr: SocketAddr) {
+        let now = unix_now();
+        self.add(addr, now);
+        if let Some(info) = self.entries.get_mut(&addr) {
+            info.last_success = Some(now);
+            info.failures = 0;
+        }
+    }
+
+    pub fn mark_failure(&mut self, addr: SocketAddr) {
+        self.add(addr, 0);
+        if let Some(info) = self.entries.get_mut(&addr) {
+            info.last_attempt = Some(unix_now());
+            info.failures += 1;
+        }
+    }
+
+    /// Up to `max` of the most recently seen addresses that are worth passing on.
+    pub fn sample(&self, max: usize) -> Vec<KnownAddr> {
+        let mut good: Vec<&AddrInfo> = self
+            .entries
+            .values()
+            .filter(|info| info.failures < MAX_SHARED_FAILURES)
+            .collect();
+        good.sort_by_key(|info| std::cmp::Reverse(info.last_seen));
+        good.iter()
+            .take(max)
+            .map(|info| KnownAddr {
+                addr: info.addr,
+                last_seen: info.last_seen,
+            })
+            .collect()
+    }
+
+    /// Every address worth dialing, those that failed least often first, then the most recently
+    /// seen.
+    pub fn candidates(&self) -> Vec<SocketAddr> {
+        let mut infos: Vec<&AddrInfo> = self.entries.values().collect();
+        infos.sort_by_key(|info| (info.failures, std::cmp::Reverse(info.last_seen)));
+        infos.iter().map(|info| info.addr).collect()
+    }
+
+    /// Make room by dropping the address with the most failures, the least recently seen first.
+    fn evict(&mut self) {
+        let worst = self
+            .entries
+            .values()
+            .max_by(|a, b| {
+                a.failures
+                    .cmp(&b.failures)
+                    .then_with(|| b.last_seen.cmp(&a.last_seen))
+            })
+            .map(|info| info.addr);
+        if let Some(addr) = worst {
+            self.entries.remove(&addr);
+        }
+    }
+
+    /// Write the address book to `path`, replacing the file atomically.
+    pub fn save(&self, path: &Path) -> std::io::Result<()> {
+        let entries: Vec<&AddrInfo> = self.entries.values().collect();
+        let bytes = bincode::serialize(&entries).map_err(std::io::Error::other)?;
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, bytes)?;
+        fs::rename(&tmp, path)
+    }
+
+    /// Read an address book written by `save`.
+    pub fn load(path: &Path) -> std::io::Result<Self> {
+        let bytes = fs::read(path)?;
+        let entries: Vec<AddrInfo> = bincode::deserialize(&bytes)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        let mut book = Self::new();
+        for info in entries.into_iter().take(MAX_ADDRESSES) {
+            book.entries.insert(info.addr, info);
+        }
+        Ok(book)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failing_addresses_are_not_shared_and_book_survives_restart() {
+        let good: SocketAddr = "127.0.0.1:6001".parse().unwrap();
+        let bad: SocketAddr = "127.0.0.1:6002".parse().unwrap();
+        let mut book = AddressBook::new();
+        book.add("0.0.0.0:6000".parse().unwrap(), 10);
+        book.add(bad, 20);
+        book.mark_success(good);
+        for _ in 0..MAX_SHARED_FAILURES {
+            book.mark_failure(bad);
+        }
+        assert_eq!(book.len(), 2);
+        let shared: Vec<SocketAddr> = book.sample(10).iter().map(|a| a.addr).collect();
+        assert_eq!(shared, vec![good]);
+
+        let path = std::env::temp_dir().join(format!("addrbook-test-{}.dat", std::process::id()));
+        book.save(&path).unwrap();
+        let loaded = AddressBook::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.len(), 2);
+        assert_eq!(loaded.get(&bad).unwrap().failures, MAX_SHARED_FAILURES);
+    }
+}
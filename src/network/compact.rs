@@ -0,0 +1,126 @@
+use crate::block::{Block, Header};
+use crate::crypto::hash::{Hashable, H256};
+use crate::mempool::Mempool;
+use crate::miner::merkle_root;
+use crate::transaction::SignedTransaction;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::convert::TryInto;
+
+/// Short transaction IDs are this many bytes of a hash salted with the block
+pub const SHORT_ID_LEN: usize = 6;
+
+pub type ShortId = [u8; SHORT_ID_LEN];
+
+/// ID of the transaction `txid` inside the block `block`. Salting with the block hash keeps
+/// collisions from being crafted ahead of time.
+pub fn short_id(block: &H256, txid: &H256) -> ShortId {
+    let mut ctx = ring::digest::Context::new(&ring::digest::SHA256);
+    ctx.update(block.as_ref());
+    ctx.update(txid.as_ref());
+    ctx.finish().as_ref()[..SHORT_ID_LEN].try_into().unwrap()
+}
+
+/// A block relayed as its header and the short IDs of its transactions, which the receiver
+/// usually has in its mempool already.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CompactBlock {
+    pub header: Header,
+    pub short_ids: Vec<ShortId>,
+}
+
+impl CompactBlock {
+    pub fn new(block: &Block) -> Self {
+        let hash = block.hash();
+        CompactBlock {
+            header: block.header.clone(),
+            short_ids: block.data.iter().map(|tx| short_id(&hash, &tx.txid())).collect(),
+        }
+    }
+
+    /// Fill in the transactions from the mempool, those not found are left for `PartialBlock::fill`.
+    pub fn reconstruct(&self, mempool: &Mempool) -> PartialBlock {
+        let hash = self.header.hash();
+        let by_short_id: HashMap<ShortId, SignedTransaction> = mempool
+            .transactions()
+            .into_iter()
+            .map(|tx| (short_id(&hash, &tx.txid()), tx))
+            .collect();
+        PartialBlock {
+            header: self.header.clone(),
+            data: self.short_ids.iter().map(|id| by_short_id.get(id).cloned()).collect(),
+        }
+    }
+}
+
+/// A block rebuilt from a compact block, with the transactions still missing
+#[derive(Debug, Clone)]
+pub struct PartialBlock {
+    pub header: Header,
+    pub data: Vec<Option<SignedTransaction>>,
+}
+
+impl PartialBlock {
+    /// Positions of the transactions to ask the sender for
+    pub fn missing(&self) -> Vec<u32> {
+        (0..self.data.len() as u32)
+            .filter(|i| self.data[*i as usize].is_none())
+            .collect()
+    }
+
+    /// Complete the block with the missing transactions in order. `None` if they don't fit in
+    /// the gaps or the result does not match the Merkle root of the header, e.g. because of a
+    /// short ID collision, in which case the full block has to be fetched.
+    pub fn fill(mut self, missing: Vec<SignedTransaction>) -> Option<Block> {
+        let gaps = self.missing();
+        if gaps.len() != missing.len() {
+            return None;
+        }
+        for (i, tx) in gaps.into_iter().zip(missing) {
+            self.data[i as usize] = Some(tx);
+        }
+        let data: Vec<SignedTransaction> = self.data.into_iter().map(Option::unwrap).collect();
+        if merkle_root(&data) != self.header.merkle_root {
+            return None;
+        }
+        Some(Block::new(self.header, data))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::key_pair;
+    use crate::transaction::{sign, Transaction};
+    use ring::signature::KeyPair;
+
+    #[test]
+    fn rebuilt_from_mempool_and_missing_transactions() {
+        let key = key_pair::random();
+        let txs: Vec<SignedTransaction> = (1..4)
+            .map(|nonce| {
+                let tx = Transaction::new([0; 20].into(), 1, nonce, 1);
+                let signature = sign(&tx, &key).as_ref().to_vec();
+                SignedTransaction::new(tx, signature, key.public_key().as_ref().to_vec())
+            })
+            .collect();
+        let header = Header::new([0; 32].into(), 0, [0xff; 32].into(), 0, merkle_root(&txs));
+        let block = Block::new(header, txs.clone());
+        let compact = CompactBlock::new(&block);
+        assert!(bincode::serialize(&compact).unwrap().len() < block.size());
+
+        // the receiver has all but the middle transaction
+        let mut mempool = Mempool::new();
+        mempool.insert(txs[0].clone(), 0).unwrap();
+        mempool.insert(txs[1].clone(), 0).unwrap();
+        mempool.insert(txs[2].clone(), 0).unwrap();
+        mempool.remove(&txs[1].txid());
+        let partial = compact.reconstruct(&mempool);
+        assert_eq!(partial.missing(), vec![1]);
+        assert!(partial.clone().fill(vec![]).is_none());
+        assert!(partial.clone().fill(vec![txs[0].clone()]).is_none());
+        let rebuilt = partial.fill(vec![txs[1].clone()]).unwrap();
+        assert_eq!(rebuilt.hash(), block.hash());
+        assert_eq!(rebuilt.data.len(), 3);
+    }
+}
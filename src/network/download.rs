@@ -0,0 +1,119 @@
+use crate::crypto::hash::H256;
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// How long a peer has to deliver a requested block before it is asked from another peer
+pub const BLOCK_TIMEOUT: Duration = Duration::from_secs(10);
+/// Most blocks asked from one peer and not received yet
+pub const MAX_IN_FLIGHT_PER_PEER: usize = 64;
+/// How long a peer that let a request time out is not asked for blocks
+pub const STALL_BACKOFF: Duration = Duration::from_secs(60);
+
+struct Request {
+    peer: SocketAddr,
+    sent: Instant,
+}
+
+/// Blocks asked for and not received yet, each from exactly one peer.
+#[derive(Default)]
+pub struct Downloads {
+    in_flight: HashMap<H256, Request>,
+    /// Peers that let requests time out, with the time they may be asked again
+    stalling: HashMap<SocketAddr, Instant>,
+}
+
+impl Downloads {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_requested(&self, hash: &H256) -> bool {
+        self.in_flight.contains_key(hash)
+    }
+
+    /// Number of blocks `peer` still has to deliver.
+    pub fn in_flight(&self, peer: &SocketAddr) -> usize {
+        self.in_flight.values().filter(|r| r.peer == *peer).count()
+    }
+
+    /// Record that `hashes` are asked from `peer`, returning those not asked from anyone yet.
+    pub fn start(&mut self, peer: SocketAddr, hashes: &[H256], now: Instant) -> Vec<H256> {
+        let mut fresh = Vec::new();
+        for hash in hashes {
+            if !self.in_flight.contains_key(hash) {
+                self.in_flight.insert(*hash, Request { peer, sent: now });
+                fresh.push(*hash);
+            }
+        }
+        fresh
+    }
+
+    pub fn received(&mut self, hash: &H256) {
+        self.in_flight.remove(hash);
+    }
+
+    /// Whether `peer` let a request time out recently and should not be asked now.
+    pub fn is_stalling(&self, peer: &SocketAddr, now: Instant) -> bool {
+        self.stalling.get(peer).is_some_and(|until| *until > now)
+    }
+
+    /// Forget the requests older than `timeout` or to peers not `connected` any more, returning
+    /// them so they can be asked from someone else. Connected peers that timed out are not asked
+    /// again for `STALL_BACKOFF`.
+    pub fn stalled(
+        &mut self,
+        connected: &HashSet<SocketAddr>,
+        now: Instant,
+        timeout: Duration,
+    ) -> Vec<(H256, SocketAddr)> {
+        let stalled: Vec<(H256, SocketAddr)> = self
+            .in_flight
+            .iter()
+            .filter(|(_, r)| !connected.contains(&r.peer) || now.duration_since(r.sent) >= timeout)
+            .map(|(hash, r)| (*hash, r.peer))
+            .collect();
+        self.stalling.retain(|_, until| *until > now);
+        for (hash, peer) in &stalled {
+            self.in_flight.remove(hash);
+            if connected.contains(peer) {
+                self.stalling.insert(*peer, now + STALL_BACKOFF);
+            }
+        }
+        stalled
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn duplicates_are_suppressed_and_slow_requests_expire() {
+        let fast: SocketAddr = "127.0.0.1:6001".parse().unwrap();
+        let slow: SocketAddr = "127.0.0.1:6002".parse().unwrap();
+        let gone: SocketAddr = "127.0.0.1:6003".parse().unwrap();
+        let hashes: Vec<H256> = (0..4u8).map(|i| [i; 32].into()).collect();
+        let start = Instant::now();
+        let mut downloads = Downloads::new();
+        assert_eq!(downloads.start(slow, &hashes[0..2], start), hashes[0..2].to_vec());
+        assert_eq!(downloads.start(fast, &hashes[1..3], start), vec![hashes[2]]);
+        assert_eq!(downloads.start(gone, &hashes[3..4], start), vec![hashes[3]]);
+        assert_eq!(downloads.in_flight(&slow), 2);
+        downloads.received(&hashes[1]);
+        downloads.received(&hashes[2]);
+
+        let connected: HashSet<SocketAddr> = [fast, slow].iter().copied().collect();
+        let mut stalled = downloads.stalled(&connected, start, BLOCK_TIMEOUT);
+        assert_eq!(stalled, vec![(hashes[3], gone)]);
+        stalled = downloads.stalled(&connected, start + BLOCK_TIMEOUT, BLOCK_TIMEOUT);
+        assert_eq!(stalled, vec![(hashes[0], slow)]);
+        assert!(!downloads.is_requested(&hashes[0]));
+        // the peer that timed out is left alone for a while, the one that went away is forgotten
+        assert!(downloads.is_stalling(&slow, start + BLOCK_TIMEOUT));
+        assert!(!downloads.is_stalling(&slow, start + BLOCK_TIMEOUT + STALL_BACKOFF));
+        assert!(!downloads.is_stalling(&gone, start));
+        // the block can be asked from someone else now
+        assert_eq!(downloads.start(fast, &hashes[0..1], start), vec![hashes[0]]);
+    }
+}
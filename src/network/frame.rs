@@ -0,0 +1,157 @@
+use super::message::Message;
+use crate::params::ChainParams;
+use std::convert::TryInto;
+
+/// Size of the header in front of every message: magic, message type, payload length, checksum
+pub const HEADER_LEN: usize = 13;
+
+/// Framing rules of the network a node runs on
+#[derive(Clone, Copy, Debug)]
+pub struct Config {
+    /// Marks frames of this network, so nodes of different networks hang up on each other
+    pub magic: [u8; 4],
+    /// Longest payload accepted, larger frames close the connection
+    pub max_payload: usize,
+}
+
+impl Config {
+    pub fn new(params: &ChainParams) -> Self {
+        Config {
+            magic: params.magic,
+            max_payload: params.max_message_bytes,
+        }
+    }
+}
+
+/// Ways a frame can break the framing rules.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum FrameError {
+    BadMagic([u8; 4]),
+    Oversized(usize),
+    BadChecksum,
+    /// The payload does not decode to a message of the type in the header
+    Malformed(u8),
+}
+
+impl std::fmt::Display for FrameError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            FrameError::BadMagic(magic) => write!(f, "wrong network magic {}", hex::encode(magic)),
+            FrameError::Oversized(len) => write!(f, "payload of {} bytes over the limit", len),
+            FrameError::BadChecksum => write!(f, "payload checksum mismatch"),
+            FrameError::Malformed(t) => write!(f, "payload is not a message of type {}", t),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+impl From<FrameError> for std::io::Error {
+    fn from(e: FrameError) -> Self {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, e)
+    }
+}
+
+/// First four bytes of the SHA256 of the payload
+fn checksum(payload: &[u8]) -> [u8; 4] {
+    let digest = ring::digest::digest(&ring::digest::SHA256, payload);
+    digest.as_ref()[..4].try_into().unwrap()
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Header {
+    pub msg_type: u8,
+    pub length: usize,
+    pub checksum: [u8; 4],
+}
+
+impl Header {
+    /// Check a received header against the framing rules.
+    pub fn parse(bytes: &[u8; HEADER_LEN], config: &Config) -> Result<Header, FrameError> {
+        let magic: [u8; 4] = bytes[0..4].try_into().unwrap();
+        if magic != config.magic {
+            return Err(FrameError::BadMagic(magic));
+        }
+        let length = u32::from_be_bytes(bytes[5..9].try_into().unwrap()) as usize;
+        if length > config.max_payload {
+            return Err(FrameError::Oversized(length));
+        }
+        Ok(Header {
+            msg_type: bytes[4],
+            length,
+            checksum: bytes[9..13].try_into().unwrap(),
+        })
+    }
+}
+
+/// A received message whose header has been checked, decoded on demand.
+pub struct Frame {
+    pub msg_type: u8,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    /// Check the payload of a frame against its header.
+    pub fn new(header: &Header, payload: Vec<u8>) -> Result<Frame, FrameError> {
+        if checksum(&payload) != header.checksum {
+            return Err(FrameError::BadChecksum);
+        }
+        Ok(Frame {
+            msg_type: header.msg_type,
+            payload,
+        })
+    }
+
+    pub fn message(&self) -> Result<Message, FrameError> {
+        match bincode::deserialize::<Message>(&self.payload) {
+            Ok(msg) if msg.msg_type() == self.msg_type => Ok(msg),
+            _ => Err(FrameError::Malformed(self.msg_type)),
+        }
+    }
+}
+
+/// Header and payload of `msg`, ready to be written to the socket.
+pub fn encode(msg: &Message, config: &Config) -> Vec<u8> {
+    let payload = bincode::serialize(msg).unwrap();
+    let mut frame = Vec::with_capacity(HEADER_LEN + payload.len());
+    frame.extend_from_slice(&config.magic);
+    frame.push(msg.msg_type());
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&checksum(&payload));
+    frame.extend_from_slice(&payload);
+    frame
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn split(bytes: &[u8]) -> ([u8; HEADER_LEN], Vec<u8>) {
+        (bytes[..HEADER_LEN].try_into().unwrap(), bytes[HEADER_LEN..].to_vec())
+    }
+
+    #[test]
+    fn frames_break_on_any_violation() {
+        let config = Config::new(&ChainParams::regtest());
+        let msg = Message::Ping("hello".to_string());
+        let (header, payload) = split(&encode(&msg, &config));
+        let parsed = Header::parse(&header, &config).unwrap();
+        assert_eq!(parsed.length, payload.len());
+        let frame = Frame::new(&parsed, payload.clone()).unwrap();
+        assert!(matches!(frame.message(), Ok(Message::Ping(ref s)) if s == "hello"));
+
+        let main = Config::new(&ChainParams::main());
+        assert_eq!(Header::parse(&header, &main), Err(FrameError::BadMagic(config.magic)));
+        let small = Config { max_payload: payload.len() - 1, ..config };
+        assert_eq!(Header::parse(&header, &small), Err(FrameError::Oversized(payload.len())));
+        let mut corrupted = payload.clone();
+        corrupted[0] ^= 1;
+        assert!(matches!(Frame::new(&parsed, corrupted), Err(FrameError::BadChecksum)));
+        // a valid payload under the wrong type
+        let mut retyped = header;
+        retyped[4] = Message::Verack.msg_type();
+        let parsed = Header::parse(&retyped, &config).unwrap();
+        let frame = Frame::new(&parsed, payload).unwrap();
+        assert!(matches!(frame.message(), Err(FrameError::Malformed(_))));
+    }
+}
@@ -0,0 +1,126 @@
+use crate::blockchain::Blockchain;
+use crate::crypto::hash::H256;
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+/// Version of the peer to peer protocol spoken by this build
+pub const PROTOCOL_VERSION: u32 = 1;
+/// Oldest protocol version we can talk to
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// First message on every connection, in both directions. The connection is usable once each side
+/// has accepted the other's version and answered with `Verack`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Version {
+    pub protocol_version: u32,
+    pub genesis: H256,
+    pub best_height: u32,
+    /// Random for every node process, to detect connections to ourselves
+    pub nonce: u64,
+    /// Where the sender accepts connections
+    pub listen_addr: SocketAddr,
+}
+
+/// Reasons for refusing a peer during the handshake.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum HandshakeError {
+    IncompatibleVersion(u32),
+    WrongGenesis(H256),
+    SelfConnection,
+}
+
+impl std::fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            HandshakeError::IncompatibleVersion(v) => write!(f, "incompatible protocol version {}", v),
+            HandshakeError::WrongGenesis(hash) => write!(f, "different genesis block {}", hash),
+            HandshakeError::SelfConnection => write!(f, "connected to ourselves"),
+        }
+    }
+}
+
+/// What this node tells its peers about itself.
+#[derive(Clone)]
+pub struct Local {
+    blockchain: Arc<Mutex<Blockchain>>,
+    genesis: H256,
+    nonce: u64,
+    listen_addr: SocketAddr,
+}
+
+impl Local {
+    pub fn new(blockchain: &Arc<Mutex<Blockchain>>, listen_addr: SocketAddr) -> Self {
+        let genesis = blockchain.lock().unwrap().genesis();
+        Local {
+            blockchain: Arc::clone(blockchain),
+            genesis,
+            nonce: rand::random(),
+            listen_addr,
+        }
+    }
+
+    pub fn version(&self) -> Version {
+        let blc = self.blockchain.lock().unwrap();
+        let best_height = blc.heights[&blc.tip()];
+        drop(blc);
+        Version {
+            protocol_version: PROTOCOL_VERSION,
+            genesis: self.genesis,
+            best_height,
+            nonce: self.nonce,
+            listen_addr: self.listen_addr,
+        }
+    }
+
+    /// Locator of our header chain if the peer announcing `version` is ahead of us.
+    pub fn locator_if_behind(&self, version: &Version) -> Option<Vec<H256>> {
+        let blc = self.blockchain.lock().unwrap();
+        let best_height = blc.headers[&blc.best_header()].1;
+        if version.best_height > best_height {
+            Some(blc.locator())
+        } else {
+            None
+        }
+    }
+
+    /// Whether we can talk to a peer announcing `version`.
+    pub fn check(&self, version: &Version) -> Result<(), HandshakeError> {
+        if version.nonce == self.nonce {
+            return Err(HandshakeError::SelfConnection);
+        }
+        if version.protocol_version < MIN_PROTOCOL_VERSION {
+            return Err(HandshakeError::IncompatibleVersion(version.protocol_version));
+        }
+        if version.genesis != self.genesis {
+            return Err(HandshakeError::WrongGenesis(version.genesis));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::params::ChainParams;
+
+    #[test]
+    fn refuses_other_networks_and_ourselves() {
+        let addr = "127.0.0.1:6000".parse().unwrap();
+        let main = Local::new(&Arc::new(Mutex::new(Blockchain::new())), addr);
+        let other_main = Local::new(&Arc::new(Mutex::new(Blockchain::new())), addr);
+        let regtest = Local::new(
+            &Arc::new(Mutex::new(Blockchain::with_params(ChainParams::regtest()))),
+            addr,
+        );
+        assert_eq!(main.check(&other_main.version()), Ok(()));
+        assert_eq!(main.check(&main.version()), Err(HandshakeError::SelfConnection));
+        assert_eq!(
+            main.check(&regtest.version()),
+            Err(HandshakeError::WrongGenesis(regtest.genesis))
+        );
+        let mut old = other_main.version();
+        old.protocol_version = MIN_PROTOCOL_VERSION - 1;
+        assert_eq!(main.check(&old), Err(HandshakeError::IncompatibleVersion(old.protocol_version)));
+    }
+}
@@ -0,0 +1,72 @@
+use crate::crypto::hash::H256;
+use std::collections::{HashSet, VecDeque};
+
+/// Most hashes remembered per peer, the oldest are forgotten first
+const MAX_KNOWN: usize = 10_000;
+
+/// Something new to tell peers about
+#[derive(Debug, Clone)]
+pub enum Inventory {
+    Blocks(Vec<H256>),
+    Transactions(Vec<H256>),
+}
+
+/// Block and transaction hashes a peer is known to have, because it sent them to us or we sent
+/// them to it.
+#[derive(Default)]
+pub struct KnownInventory {
+    hashes: HashSet<H256>,
+    order: VecDeque<H256>,
+}
+
+impl KnownInventory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn contains(&self, hash: &H256) -> bool {
+        self.hashes.contains(hash)
+    }
+
+    /// Remember `hash`, returns whether it was unknown.
+    pub fn insert(&mut self, hash: H256) -> bool {
+        if !self.hashes.insert(hash) {
+            return false;
+        }
+        self.order.push_back(hash);
+        if self.order.len() > MAX_KNOWN {
+            if let Some(oldest) = self.order.pop_front() {
+                self.hashes.remove(&oldest);
+            }
+        }
+        true
+    }
+
+    /// The hashes of `hashes` the peer lacks, remembered as known from now on.
+    pub fn unknown(&mut self, hashes: &[H256]) -> Vec<H256> {
+        hashes.iter().copied().filter(|hash| self.insert(*hash)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn announces_each_hash_once_and_forgets_the_oldest() {
+        let mut known = KnownInventory::new();
+        let a: H256 = [1; 32].into();
+        let b: H256 = [2; 32].into();
+        assert!(known.insert(a));
+        assert_eq!(known.unknown(&[a, b, b]), vec![b]);
+        assert!(known.unknown(&[a, b]).is_empty());
+        for i in 0..MAX_KNOWN as u32 {
+            let mut bytes = [0xff; 32];
+            bytes[..4].copy_from_slice(&i.to_be_bytes());
+            known.insert(bytes.into());
+        }
+        assert!(!known.contains(&a));
+        assert!(!known.contains(&b));
+        assert_eq!(known.order.len(), MAX_KNOWN);
+    }
+}
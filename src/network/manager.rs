@@ -0,0 +1,211 @@
+use super::addrbook::AddressBook;
+use super::peer::Direction;
+use super::server::Handle as ServerHandle;
+use log::{debug, info};
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often the outbound connections are checked
+const TICK: Duration = Duration::from_secs(1);
+/// Delay before redialing an address after its first failure, doubled with every further one
+const BASE_BACKOFF: Duration = Duration::from_secs(1);
+/// Longest delay between two attempts on the same address
+const MAX_BACKOFF: Duration = Duration::from_secs(64);
+/// Most addresses dialed in one tick, each dial may take a few seconds
+const MAX_DIALS_PER_TICK: usize = 4;
+
+/// Delay before the next attempt on an address that failed `failures` times in a row.
+fn backoff(failures: u32) -> Duration {
+    if failures == 0 {
+        return Duration::from_secs(0);
+    }
+    let factor = 1u32.checked_shl(failures - 1).unwrap_or(u32::MAX);
+    BASE_BACKOFF
+        .checked_mul(factor)
+        .map_or(MAX_BACKOFF, |delay| delay.min(MAX_BACKOFF))
+}
+
+/// Redial bookkeeping of one address
+#[derive(Default)]
+struct Dial {
+    failures: u32,
+    next_attempt: Option<Instant>,
+}
+
+/// Keeps `target` outbound connections open, dialing seeds first and then the address book.
+pub struct Context {
+    server: ServerHandle,
+    addrbook: Arc<Mutex<AddressBook>>,
+    /// Addresses given on the command line, always preferred
+    seeds: Vec<SocketAddr>,
+    target: usize,
+    /// Our own listening address, never dialed
+    listen_addr: SocketAddr,
+    dials: HashMap<SocketAddr, Dial>,
+    /// Outbound peers seen connected on the previous tick
+    connected: HashSet<SocketAddr>,
+}
+
+pub fn new(
+    server: &ServerHandle,
+    addrbook: &Arc<Mutex<AddressBook>>,
+    seeds: Vec<SocketAddr>,
+    target: usize,
+    listen_addr: SocketAddr,
+) -> Context {
+    Context {
+        server: server.clone(),
+        addrbook: Arc::clone(addrbook),
+        seeds,
+        target,
+        listen_addr,
+        dials: HashMap::new(),
+        connected: HashSet::new(),
+    }
+}
+
+impl Context {
+    pub fn start(mut self) {
+        info!("Peer manager started, keeping {} outbound peers", self.target);
+        thread::Builder::new()
+            .name("peer-manager".to_string())
+            .spawn(move || loop {
+                self.maintain();
+                thread::sleep(TICK);
+            })
+            .unwrap();
+    }
+
+    /// Notice dropped peers and dial new ones until the target is met.
+    fn maintain(&mut self) {
+        // the server learns listen addresses before they reach the address book, so with the
+        // candidates taken first every connected one shows up among the peers
+        let candidates = self.candidates();
+        let peers = self.server.peers();
+        let now = Instant::now();
+        let outbound: HashSet<SocketAddr> = peers
+            .iter()
+            .filter(|p| p.direction == Direction::Outgoing)
+            .map(|p| p.addr)
+            .collect();
+        for peer in &peers {
+            if peer.direction == Direction::Outgoing && peer.ready {
+                self.dials.entry(peer.addr).or_default().failures = 0;
+            }
+        }
+        for addr in self.connected.difference(&outbound) {
+            info!("Outbound peer {} went away", addr);
+            let dial = self.dials.entry(*addr).or_default();
+            dial.failures += 1;
+            dial.next_attempt = Some(now + backoff(dial.failures));
+        }
+        self.connected = outbound;
+
+        // inbound peers already connected to us don't need to be dialed
+        let mut busy: HashSet<SocketAddr> = self.connected.clone();
+        busy.extend(peers.iter().filter_map(|p| p.listen_addr));
+        busy.insert(self.listen_addr);
+
+        let mut missing = self.target.saturating_sub(self.connected.len());
+        let mut dials = 0;
+        for addr in candidates {
+            if missing == 0 || dials == MAX_DIALS_PER_TICK {
+                break;
+            }
+            if busy.contains(&addr) {
+                continue;
+            }
+            let dial = self.dials.entry(addr).or_default();
+            if dial.next_attempt.is_some_and(|t| t > now) {
+                continue;
+            }
+            dials += 1;
+            self.addrbook.lock().unwrap().mark_attempt(addr);
+            match self.server.connect(addr) {
+                Ok(_) => {
+                    info!("Connected to outgoing peer {}", addr);
+                    self.connected.insert(addr);
+                    missing -= 1;
+                }
+                Err(e) => {
+                    self.addrbook.lock().unwrap().mark_failure(addr);
+                    dial.failures += 1;
+                    let delay = backoff(dial.failures);
+                    dial.next_attempt = Some(now + delay);
+                    debug!("Error connecting to peer {}, retrying in {:?}: {}", addr, delay, e);
+                }
+            }
+        }
+    }
+
+    /// Addresses worth dialing, best first.
+    fn candidates(&self) -> Vec<SocketAddr> {
+        let mut candidates = self.seeds.clone();
+        for addr in self.addrbook.lock().unwrap().candidates() {
+            if !candidates.contains(&addr) {
+                candidates.push(addr);
+            }
+        }
+        candidates
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        assert_eq!(backoff(0), Duration::from_secs(0));
+        assert_eq!(backoff(1), BASE_BACKOFF);
+        assert_eq!(backoff(3), BASE_BACKOFF * 4);
+        assert_eq!(backoff(40), MAX_BACKOFF);
+    }
+
+    /// An address nothing listens on
+    fn dead_addr() -> SocketAddr {
+        std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap()
+    }
+
+    #[test]
+    fn dropped_and_dead_peers_back_off() {
+        let (msg_sender, _msg_receiver) = crossbeam::channel::unbounded();
+        let blockchain = Arc::new(Mutex::new(crate::blockchain::Blockchain::new()));
+        let addr = dead_addr();
+        let local = crate::network::handshake::Local::new(&blockchain, addr);
+        let frame_config = crate::network::frame::Config::new(&blockchain.lock().unwrap().params);
+        let addrbook = Arc::new(Mutex::new(AddressBook::new()));
+        let (server_ctx, server) =
+            crate::network::server::new(addr, msg_sender, frame_config, None, local, &addrbook).unwrap();
+        server_ctx.start().unwrap();
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let live = listener.local_addr().unwrap();
+        let mut seeds = vec![live];
+        seeds.extend((0..2 * MAX_DIALS_PER_TICK).map(|_| dead_addr()));
+        let mut manager = new(&server, &addrbook, seeds, 100, addr);
+
+        // one tick dials a few addresses only
+        manager.maintain();
+        assert!(manager.connected.contains(&live));
+        let failed = manager.dials.values().filter(|dial| dial.failures == 1).count();
+        assert_eq!(failed, MAX_DIALS_PER_TICK - 1);
+
+        // the peer hangs up
+        drop(listener.accept().unwrap());
+        drop(listener);
+        while server.peers().iter().any(|peer| peer.addr == live) {
+            thread::sleep(Duration::from_millis(10));
+        }
+        manager.maintain();
+        assert!(!manager.connected.contains(&live));
+        assert_eq!(manager.dials[&live].failures, 1);
+        // the addresses that failed wait, the next ones are dialed instead
+        let failed = manager.dials.values().filter(|dial| dial.failures == 1).count();
+        assert_eq!(failed, 2 * MAX_DIALS_PER_TICK);
+        assert!(manager.dials.values().all(|dial| dial.failures <= 1));
+    }
+}
@@ -1,16 +1,58 @@
 use serde::{Serialize, Deserialize};
 use crate::crypto::hash::H256;
-use crate::block::Block;
+use crate::block::{Block, Header};
+use super::addrbook::KnownAddr;
+use super::compact::CompactBlock;
+use super::handshake::Version;
 use crate::transaction::*;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Message {
+    Version(Version),
+    Verack,
     Ping(String),
     Pong(String),
     NewBLockHashes(Vec<H256>),
     GetBlocks(Vec<H256>),
     Blocks(Vec<Block>),
+    // transactions are always referred to by `SignedTransaction::txid`
     NewTransactionHashes(Vec<H256>),
     GetTransactions(Vec<H256>),
     Transactions(Vec<SignedTransaction>),
+    GetAddr,
+    Addr(Vec<KnownAddr>),
+    /// Block locator of the sender's best header chain
+    GetHeaders(Vec<H256>),
+    Headers(Vec<Header>),
+    GetCompactBlocks(Vec<H256>),
+    CompactBlock(CompactBlock),
+    /// Transactions of a compact block missing from the mempool, by position in the block
+    GetBlockTransactions(H256, Vec<u32>),
+    BlockTransactions(H256, Vec<SignedTransaction>),
+}
+
+impl Message {
+    /// The message type carried in the frame header
+    pub fn msg_type(&self) -> u8 {
+        match self {
+            Message::Version(_) => 0,
+            Message::Verack => 1,
+            Message::Ping(_) => 2,
+            Message::Pong(_) => 3,
+            Message::NewBLockHashes(_) => 4,
+            Message::GetBlocks(_) => 5,
+            Message::Blocks(_) => 6,
+            Message::NewTransactionHashes(_) => 7,
+            Message::GetTransactions(_) => 8,
+            Message::Transactions(_) => 9,
+            Message::GetAddr => 10,
+            Message::Addr(_) => 11,
+            Message::GetHeaders(_) => 12,
+            Message::Headers(_) => 13,
+            Message::GetCompactBlocks(_) => 14,
+            Message::CompactBlock(_) => 15,
+            Message::GetBlockTransactions(_, _) => 16,
+            Message::BlockTransactions(_, _) => 17,
+        }
+    }
 }
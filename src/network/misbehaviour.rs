@@ -0,0 +1,115 @@
+use crate::crypto::hash::H160;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// Peers whose score reaches this are disconnected and banned
+pub const BAN_THRESHOLD: u32 = 100;
+/// How long a ban lasts
+pub const BAN_DURATION: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Things a peer can do wrong, each adding to its misbehaviour score.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Misbehaviour {
+    /// A message that could not be decoded
+    MalformedMessage,
+    /// A message over the protocol limits
+    OversizedMessage,
+    /// A block whose hash does not meet its target, or with the wrong target
+    InvalidProofOfWork,
+    /// A block over the limits of the chain params
+    InvalidBlock,
+    /// A transaction not signed by the key it carries
+    InvalidSignature,
+}
+
+impl Misbehaviour {
+    pub fn score(self) -> u32 {
+        match self {
+            Misbehaviour::MalformedMessage => 20,
+            Misbehaviour::OversizedMessage => 20,
+            Misbehaviour::InvalidProofOfWork => 50,
+            Misbehaviour::InvalidBlock => 50,
+            Misbehaviour::InvalidSignature => 20,
+        }
+    }
+}
+
+impl std::fmt::Display for Misbehaviour {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Misbehaviour::MalformedMessage => write!(f, "malformed message"),
+            Misbehaviour::OversizedMessage => write!(f, "oversized message"),
+            Misbehaviour::InvalidProofOfWork => write!(f, "invalid proof of work"),
+            Misbehaviour::InvalidBlock => write!(f, "invalid block"),
+            Misbehaviour::InvalidSignature => write!(f, "invalid transaction signature"),
+        }
+    }
+}
+
+/// Who a score or a ban applies to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Offender {
+    /// The node key the peer proved to hold in an encrypted session
+    Node(H160),
+    /// The address the peer listens on, or the one it connects from until it tells us
+    Addr(SocketAddr),
+}
+
+/// Misbehaviour scores, and the peers we refuse to talk to for a while. Both outlive the
+/// connection, so a peer cannot clear its score by reconnecting. Only the offending address is
+/// banned, not its whole IP: on a local testnet every node shares 127.0.0.1.
+#[derive(Default)]
+pub struct BanList {
+    scores: HashMap<Offender, u32>,
+    until: HashMap<Offender, Instant>,
+}
+
+impl BanList {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add `offence` to the score of `offender`, returns the new score.
+    pub fn add(&mut self, offender: Offender, offence: Misbehaviour) -> u32 {
+        let score = self.scores.entry(offender).or_insert(0);
+        *score += offence.score();
+        *score
+    }
+
+    /// Ban `offender` for `duration`, starting over from a clean score afterwards.
+    pub fn ban(&mut self, offender: Offender, duration: Duration) {
+        self.scores.remove(&offender);
+        self.until.insert(offender, Instant::now() + duration);
+    }
+
+    /// Whether `offender` is banned, forgetting bans that ran out.
+    pub fn is_banned(&mut self, offender: &Offender) -> bool {
+        let now = Instant::now();
+        self.until.retain(|_, until| *until > now);
+        self.until.contains_key(offender)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scores_add_up_and_bans_expire() {
+        let bad = Offender::Addr("127.0.0.1:9001".parse().unwrap());
+        let neighbour = Offender::Addr("127.0.0.1:9002".parse().unwrap());
+        let forgiven = Offender::Node([1; 20].into());
+        let mut bans = BanList::new();
+        assert_eq!(bans.add(bad, Misbehaviour::MalformedMessage), 20);
+        assert_eq!(bans.add(bad, Misbehaviour::InvalidBlock), 70);
+        assert_eq!(bans.add(neighbour, Misbehaviour::MalformedMessage), 20);
+        bans.ban(bad, BAN_DURATION);
+        bans.ban(forgiven, Duration::from_secs(0));
+        assert!(bans.is_banned(&bad));
+        assert!(!bans.is_banned(&neighbour));
+        assert!(!bans.is_banned(&forgiven));
+        // the score starts over once the ban is served
+        assert_eq!(bans.add(bad, Misbehaviour::MalformedMessage), 20);
+    }
+}
@@ -1,4 +1,13 @@
+pub mod addrbook;
+pub mod compact;
+pub mod download;
+pub mod frame;
+pub mod handshake;
+pub mod inventory;
+pub mod manager;
 pub mod message;
+pub mod misbehaviour;
 pub mod peer;
 pub mod server;
+pub mod session;
 pub mod worker;
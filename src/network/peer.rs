@@ -1,75 +1,119 @@
+use super::handshake::Version;
+use super::inventory::KnownInventory;
+use super::frame::{self, Frame, Header, HEADER_LEN};
 use super::message;
+use super::misbehaviour::Offender;
+use super::session::{Handshake, Opening, Sealing, SessionError, HELLO_LEN, RECORD_HEADER_LEN, TAG_LEN};
 use log::{trace, warn};
 use mio;
+use serde::Serialize;
 use mio_extras::channel;
 use std::convert::TryInto;
 use std::io::{Read, Write};
-use std::sync::mpsc;
+use crate::crypto::hash::{H160, H256};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
 
-enum DecodeState {
-    Length,
-    Payload,
-}
+/// Most bytes taken from the socket at once
+const READ_CHUNK: usize = 16 * 1024;
 
 pub enum ReadResult {
     Continue,
-    Message(Vec<u8>),
+    Message(Frame),
+    /// The peer proved its identity, our side of the session can start encrypting
+    Session(H160, Box<Sealing>),
     EOF,
 }
 
 pub struct ReadContext {
     reader: std::io::BufReader<mio::net::TcpStream>,
-    buffer: Vec<u8>,
-    msg_length: usize,
-    read_length: usize,
-    state: DecodeState,
+    /// Bytes from the socket not yet decrypted, only used in encrypted sessions
+    incoming: Vec<u8>,
+    /// Bytes not yet split into frames
+    plain: Vec<u8>,
+    config: frame::Config,
+    /// Waiting for the hello of the peer
+    handshake: Option<Handshake>,
+    /// Decrypts the records of the peer once the session is set up
+    opening: Option<Opening>,
 }
 
 impl ReadContext {
     pub fn read(&mut self) -> std::io::Result<ReadResult> {
-        let bytes_read = self
-            .reader
-            .read(&mut self.buffer[self.read_length..self.msg_length]);
-        match bytes_read {
-            Ok(0) => {
-                trace!("Detected socket EOF");
-                Ok(ReadResult::EOF)
-            }
-            Ok(size) => {
-                trace!("Read {} bytes from socket", size);
-                // we got some data, move the cursor
-                self.read_length += size;
-                if self.read_length == self.msg_length {
-                    // buffer filled, process the buffer
-                    match self.state {
-                        DecodeState::Length => {
-                            let message_length =
-                                u32::from_be_bytes(self.buffer[0..4].try_into().unwrap());
-                            self.state = DecodeState::Payload;
-                            self.read_length = 0;
-                            self.msg_length = message_length as usize;
-                            if self.buffer.len() < self.msg_length {
-                                self.buffer.resize(self.msg_length, 0);
-                            }
-                            trace!("Received message length={}", message_length);
-                            Ok(ReadResult::Continue)
-                        }
-                        DecodeState::Payload => {
-                            let new_payload: Vec<u8> = self.buffer[0..self.msg_length].to_vec();
-                            self.state = DecodeState::Length;
-                            self.read_length = 0;
-                            self.msg_length = std::mem::size_of::<u32>();
-                            trace!("Received full message");
-                            Ok(ReadResult::Message(new_payload))
-                        }
-                    }
-                } else {
-                    Ok(ReadResult::Continue)
+        // hand over what is buffered before touching the socket again
+        if let Some(frame) = self.next_frame()? {
+            return Ok(ReadResult::Message(frame));
+        }
+        let mut chunk = [0; READ_CHUNK];
+        let size = self.reader.read(&mut chunk)?;
+        if size == 0 {
+            trace!("Detected socket EOF");
+            return Ok(ReadResult::EOF);
+        }
+        trace!("Read {} bytes from socket", size);
+        if self.handshake.is_none() && self.opening.is_none() {
+            self.plain.extend_from_slice(&chunk[..size]);
+        } else {
+            self.incoming.extend_from_slice(&chunk[..size]);
+            if let Some(handshake) = self.handshake.take() {
+                if self.incoming.len() < HELLO_LEN {
+                    self.handshake = Some(handshake);
+                    return Ok(ReadResult::Continue);
                 }
+                let hello: [u8; HELLO_LEN] = self.incoming[..HELLO_LEN].try_into().unwrap();
+                self.incoming.drain(..HELLO_LEN);
+                let (peer_id, opening, sealing) = handshake.finish(&hello)?;
+                self.opening = Some(opening);
+                self.open_records()?;
+                return Ok(ReadResult::Session(peer_id, Box::new(sealing)));
             }
-            Err(e) => Err(e),
+            self.open_records()?;
+        }
+        match self.next_frame()? {
+            Some(frame) => Ok(ReadResult::Message(frame)),
+            None => Ok(ReadResult::Continue),
         }
     }
+
+    /// Decrypt the complete records received so far.
+    fn open_records(&mut self) -> std::io::Result<()> {
+        let opening = match &mut self.opening {
+            Some(opening) => opening,
+            None => return Ok(()),
+        };
+        while self.incoming.len() >= RECORD_HEADER_LEN {
+            let length = u32::from_be_bytes(self.incoming[..RECORD_HEADER_LEN].try_into().unwrap()) as usize;
+            if length > HEADER_LEN + self.config.max_payload + TAG_LEN {
+                return Err(SessionError::Oversized(length).into());
+            }
+            if self.incoming.len() < RECORD_HEADER_LEN + length {
+                break;
+            }
+            let plain = opening.open(&mut self.incoming[RECORD_HEADER_LEN..RECORD_HEADER_LEN + length])?;
+            self.plain.extend_from_slice(plain);
+            self.incoming.drain(..RECORD_HEADER_LEN + length);
+        }
+        Ok(())
+    }
+
+    /// Take the next complete frame out of the buffer.
+    fn next_frame(&mut self) -> std::io::Result<Option<Frame>> {
+        if self.plain.len() < HEADER_LEN {
+            return Ok(None);
+        }
+        let header = Header::parse(self.plain[..HEADER_LEN].try_into().unwrap(), &self.config)?;
+        if self.plain.len() < HEADER_LEN + header.length {
+            return Ok(None);
+        }
+        trace!("Received message type={} length={}", header.msg_type, header.length);
+        let payload = self.plain[HEADER_LEN..HEADER_LEN + header.length].to_vec();
+        self.plain.drain(..HEADER_LEN + header.length);
+        if self.plain.is_empty() {
+            // large payloads are rare, don't hold on to their memory
+            self.plain.shrink_to(READ_CHUNK);
+        }
+        Ok(Some(Frame::new(&header, payload)?))
+    }
 }
 
 pub enum WriteResult {
@@ -78,112 +122,97 @@ pub enum WriteResult {
     ChanClosed,
 }
 
-enum WriteState {
-    Length,
-    Payload,
-}
-
 pub struct WriteContext {
     writer: std::io::BufWriter<mio::net::TcpStream>,
     pub queue: channel::Receiver<Vec<u8>>,
-    len_buffer: [u8; std::mem::size_of::<u32>()],
     msg_buffer: Vec<u8>,
-    msg_length: usize,
     written_length: usize,
-    state: WriteState,
+    /// Whether frames wait for a session before they are sent
+    encrypted: bool,
+    /// Encrypts the frames once the session is set up
+    sealing: Option<Sealing>,
 }
 
 impl WriteContext {
     pub fn write(&mut self) -> std::io::Result<WriteResult> {
         loop {
-            match self.state {
-                WriteState::Length => {
-                    if self.written_length == std::mem::size_of::<u32>() {
-                        // if the length part has been fully sent
-                        self.written_length = 0;
-                        self.state = WriteState::Payload;
-                        continue;
-                    } else {
-                        // we are still sending the length part
-                        let written = self.writer.write(
-                            &self.len_buffer[self.written_length..std::mem::size_of::<u32>()],
-                        )?;
-                        if written == 0 {
-                            return Ok(WriteResult::EOF);
-                        }
-                        self.written_length += written;
-                        continue;
-                    }
+            if self.written_length == self.msg_buffer.len() {
+                // if the previous frame has been fully written, try to get the next frame
+                // first flush the writer
+                self.writer.flush()?;
+                if self.encrypted && self.sealing.is_none() {
+                    // only the hello goes out before the session is set up
+                    return Ok(WriteResult::Complete);
                 }
-                WriteState::Payload => {
-                    if self.written_length == self.msg_length {
-                        // if the previous message has been fully written, try to get the next message
-                        // first flush the writer
-                        self.writer.flush()?;
-                        let msg = match self.queue.try_recv() {
-                            Ok(msg) => msg,
-                            Err(e) => match e {
-                                mpsc::TryRecvError::Empty => return Ok(WriteResult::Complete),
-                                mpsc::TryRecvError::Disconnected => {
-                                    return Ok(WriteResult::ChanClosed);
-                                }
-                            },
-                        };
-
-                        // encode the message and the length
-                        self.msg_buffer = msg;
-                        self.msg_length = self.msg_buffer.len();
-                        self.len_buffer[..4]
-                            .copy_from_slice(&(self.msg_length as u32).to_be_bytes());
-                        self.written_length = 0;
-                        self.state = WriteState::Length;
-                        continue;
-                    } else {
-                        // we are still sending the payload
-                        let written = self
-                            .writer
-                            .write(&self.msg_buffer[self.written_length..self.msg_length])?;
-                        if written == 0 {
-                            return Ok(WriteResult::EOF);
+                self.msg_buffer = match self.queue.try_recv() {
+                    Ok(frame) => match &mut self.sealing {
+                        Some(sealing) => sealing.seal(&frame),
+                        None => frame,
+                    },
+                    Err(e) => match e {
+                        mpsc::TryRecvError::Empty => return Ok(WriteResult::Complete),
+                        mpsc::TryRecvError::Disconnected => {
+                            return Ok(WriteResult::ChanClosed);
                         }
-                        self.written_length += written;
-                        continue;
-                    }
+                    },
+                };
+                self.written_length = 0;
+            } else {
+                // we are still sending the frame
+                let written = self.writer.write(&self.msg_buffer[self.written_length..])?;
+                if written == 0 {
+                    return Ok(WriteResult::EOF);
                 }
+                self.written_length += written;
             }
         }
     }
+
+    /// Start encrypting the queued frames.
+    pub fn start_session(&mut self, sealing: Box<Sealing>) {
+        self.sealing = Some(*sealing);
+    }
 }
 
+/// Set up the contexts of a new connection. With a `handshake` everything but its hello is
+/// encrypted.
 pub fn new(
     stream: mio::net::TcpStream,
     direction: Direction,
+    config: frame::Config,
+    handshake: Option<Handshake>,
 ) -> std::io::Result<(Context, Handle)> {
     let reader_stream = stream.try_clone()?;
     let writer_stream = stream.try_clone()?;
     let addr = stream.peer_addr()?;
     let bufreader = std::io::BufReader::new(reader_stream);
+    // the hello is the first thing on the wire
+    let hello = handshake.as_ref().map_or_else(Vec::new, |h| h.hello().to_vec());
+    let encrypted = handshake.is_some();
     let read_ctx = ReadContext {
         reader: bufreader,
-        buffer: vec![0; std::mem::size_of::<u32>()],
-        msg_length: std::mem::size_of::<u32>(),
-        read_length: 0,
-        state: DecodeState::Length,
+        incoming: Vec::new(),
+        plain: Vec::new(),
+        config,
+        handshake,
+        opening: None,
     };
     let bufwriter = std::io::BufWriter::new(writer_stream);
     let (write_sender, write_receiver) = channel::channel();
     let write_ctx = WriteContext {
         writer: bufwriter,
         queue: write_receiver,
-        len_buffer: [0; std::mem::size_of::<u32>()],
-        msg_buffer: Vec::new(),
-        msg_length: 0,
+        msg_buffer: hello,
         written_length: 0,
-        state: WriteState::Payload,
+        encrypted,
+        sealing: None,
     };
     let handle = Handle {
         write_queue: write_sender,
         addr,
+        config,
+        known: Arc::new(Mutex::new(KnownInventory::new())),
+        best_height: Arc::new(AtomicU32::new(0)),
     };
     let ctx = Context {
         addr,
@@ -192,11 +221,15 @@ pub fn new(
         writer: write_ctx,
         handle: handle.clone(),
         direction,
+        version: None,
+        verack_received: false,
+        peer_id: None,
+        connected_at: std::time::Instant::now(),
     };
     Ok((ctx, handle))
 }
 
-#[derive(Copy, Clone)]
+#[derive(Serialize, Debug, Copy, Clone, PartialEq, Eq)]
 pub enum Direction {
     Incoming,
     Outgoing,
@@ -209,18 +242,83 @@ pub struct Context {
     pub writer: WriteContext,
     pub handle: Handle,
     pub direction: Direction,
+    /// The version the peer announced, once we accepted it
+    pub version: Option<Version>,
+    /// Whether the peer accepted our version
+    pub verack_received: bool,
+    /// The node key the peer proved to hold, in encrypted sessions
+    pub peer_id: Option<H160>,
+    /// When the connection was set up, the handshake has to complete soon after
+    pub connected_at: std::time::Instant,
+}
+
+impl Context {
+    /// Whether the handshake completed and messages may flow.
+    pub fn is_ready(&self) -> bool {
+        self.version.is_some() && self.verack_received
+    }
+
+    /// Where the peer accepts connections: the address we dialed, or the one in its version.
+    /// Until an incoming peer sent its version, the address it connects from.
+    pub fn listen_addr(&self) -> std::net::SocketAddr {
+        match (self.direction, &self.version) {
+            (Direction::Incoming, Some(version)) => version.listen_addr,
+            _ => self.addr,
+        }
+    }
+
+    /// Who is to blame for what the peer does: its node key if it proved one, its listen address
+    /// otherwise.
+    pub fn offender(&self) -> Offender {
+        match self.peer_id {
+            Some(id) => Offender::Node(id),
+            None => Offender::Addr(self.listen_addr()),
+        }
+    }
 }
 
 #[derive(Clone)]
 pub struct Handle {
     addr: std::net::SocketAddr,
     write_queue: channel::Sender<Vec<u8>>,
+    config: frame::Config,
+    /// Inventory the peer has, shared by every clone of the handle
+    known: Arc<Mutex<KnownInventory>>,
+    /// Height of the best block the peer is known to have
+    best_height: Arc<AtomicU32>,
 }
 
 impl Handle {
+    pub fn addr(&self) -> std::net::SocketAddr {
+        self.addr
+    }
+
+    /// Remember that the peer has `hashes`.
+    pub fn mark_known(&self, hashes: &[H256]) {
+        let mut known = self.known.lock().unwrap();
+        for hash in hashes {
+            known.insert(*hash);
+        }
+    }
+
+    /// Height of the best block the peer is known to have, from its version and what it sent since.
+    pub fn best_height(&self) -> u32 {
+        self.best_height.load(Ordering::Relaxed)
+    }
+
+    /// Remember that the peer has a block at `height`.
+    pub fn saw_height(&self, height: u32) {
+        self.best_height.fetch_max(height, Ordering::Relaxed);
+    }
+
+    /// The hashes of `hashes` the peer lacks, remembered as known from now on.
+    pub fn unknown(&self, hashes: &[H256]) -> Vec<H256> {
+        self.known.lock().unwrap().unknown(hashes)
+    }
+
     pub fn write(&self, msg: message::Message) {
         // TODO: return result
-        let buffer = bincode::serialize(&msg).unwrap();
+        let buffer = frame::encode(&msg, &self.config);
         if self.write_queue.send(buffer).is_err() {
             warn!("Failed to send write request for peer {}, channel detached", self.addr);
         }
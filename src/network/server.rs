@@ -1,18 +1,36 @@
-use super::message;
+use super::addrbook::AddressBook;
+use super::frame::{self, Frame, FrameError};
+use super::handshake::{HandshakeError, Local};
+use super::inventory::Inventory;
+use super::message::{self, Message};
+use super::misbehaviour::{BanList, Misbehaviour, Offender, BAN_DURATION, BAN_THRESHOLD};
 use super::peer::{self, ReadResult, WriteResult};
+use super::session::{Handshake, NodeKey};
 use crossbeam::channel as cbchannel;
 use log::{debug, error, info, trace, warn};
 use mio::{self, net};
 use mio_extras::channel;
-use std::sync::mpsc;
+use serde::Serialize;
+use std::sync::{mpsc, Arc, Mutex};
 use std::thread;
 
 const MAX_INCOMING_CLIENT: usize = 256;
 const MAX_EVENT: usize = 1024;
+/// How long a peer has to complete the version handshake before it is dropped
+const HANDSHAKE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+/// How often the event loop wakes up to look for peers stuck in the handshake
+const HANDSHAKE_CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+/// How long dialing a peer may take. The dial blocks the caller of `Handle::connect`, never the
+/// event loop.
+const CONNECT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(3);
 
 pub fn new(
     addr: std::net::SocketAddr,
-    msg_sink: cbchannel::Sender<(Vec<u8>, peer::Handle)>,
+    msg_sink: cbchannel::Sender<(Frame, peer::Handle)>,
+    frame_config: frame::Config,
+    node_key: Option<Arc<NodeKey>>,
+    local: Local,
+    addrbook: &Arc<Mutex<AddressBook>>,
 ) -> std::io::Result<(Context, Handle)> {
     let (control_signal_sender, control_signal_receiver) = channel::channel();
     let handle = Handle {
@@ -25,6 +43,11 @@ pub fn new(
         poll: mio::Poll::new()?,
         control_chan: control_signal_receiver,
         new_msg_chan: msg_sink,
+        frame_config,
+        node_key,
+        local,
+        addrbook: Arc::clone(addrbook),
+        bans: BanList::new(),
         _handle: handle.clone(),
     };
     Ok((ctx, handle))
@@ -36,7 +59,17 @@ pub struct Context {
     addr: std::net::SocketAddr,
     poll: mio::Poll,
     control_chan: channel::Receiver<ControlSignal>,
-    new_msg_chan: cbchannel::Sender<(Vec<u8>, peer::Handle)>,
+    new_msg_chan: cbchannel::Sender<(Frame, peer::Handle)>,
+    /// Magic and size limit of the frames on our network
+    frame_config: frame::Config,
+    /// Signs the hellos of encrypted sessions, `None` if connections are plaintext
+    node_key: Option<Arc<NodeKey>>,
+    /// What we announce in the handshake
+    local: Local,
+    /// Records which addresses we could connect to
+    addrbook: Arc<Mutex<AddressBook>>,
+    /// Peers that misbehaved too often
+    bans: BanList,
     _handle: Handle,
 }
 
@@ -79,7 +112,12 @@ impl Context {
             mio::Ready::readable(),
             mio::PollOpt::edge(),
         )?;
-        let (ctx, handle) = peer::new(stream, direction)?;
+        let magic = self.frame_config.magic;
+        let handshake = self
+            .node_key
+            .as_ref()
+            .map(|key| Handshake::new(key, magic, direction == peer::Direction::Outgoing));
+        let (ctx, handle) = peer::new(stream, direction, self.frame_config, handshake)?;
 
         // register the writer queue
         self.poll.register(
@@ -89,6 +127,9 @@ impl Context {
             mio::PollOpt::edge() | mio::PollOpt::oneshot(),
         )?;
 
+        // both sides open with their version, nothing else is accepted before the handshake
+        handle.write(Message::Version(self.local.version()));
+
         // insert the context and return the handle
         vacant.insert(ctx);
         // record the key of this peer
@@ -97,11 +138,19 @@ impl Context {
         Ok(handle)
     }
 
-    /// Connect to a peer, and register this peer
-    fn connect(&mut self, addr: &std::net::SocketAddr) -> std::io::Result<peer::Handle> {
-        // we need to estabilsh a stdlib tcp stream, since we need it to block
+    /// Register a peer the caller of `Handle::connect` dialed
+    fn connect(
+        &mut self,
+        addr: &std::net::SocketAddr,
+        stream: std::net::TcpStream,
+    ) -> std::io::Result<peer::Handle> {
         debug!("Establishing connection to peer {}", addr);
-        let stream = std::net::TcpStream::connect(addr)?;
+        if self.bans.is_banned(&Offender::Addr(*addr)) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                "peer is banned",
+            ));
+        }
         let mio_stream = net::TcpStream::from_stream(stream)?;
         self.register(mio_stream, peer::Direction::Outgoing)
     }
@@ -113,6 +162,10 @@ impl Context {
         addr: std::net::SocketAddr,
     ) -> std::io::Result<()> {
         debug!("New incoming connection from {}", addr);
+        if self.bans.is_banned(&Offender::Addr(addr)) {
+            info!("Refusing banned peer {}", addr);
+            return Ok(());
+        }
         match self.register(stream, peer::Direction::Incoming) {
             Ok(_) => {
                 info!("Connected to incoming peer {}", addr);
@@ -128,19 +181,131 @@ impl Context {
         match req {
             ControlSignal::ConnectNewPeer(req) => {
                 trace!("Processing ConnectNewPeer command");
-                let handle = self.connect(&req.addr);
+                let handle = self.connect(&req.addr, req.stream);
                 req.result_chan.send(handle).unwrap();
             }
             ControlSignal::BroadcastMessage(msg) => {
                 trace!("Processing BroadcastMessage command");
                 for peer_id in &self.peer_list {
-                    self.peers[*peer_id].handle.write(msg.clone());
+                    let peer = &self.peers[*peer_id];
+                    if peer.is_ready() {
+                        peer.handle.write(msg.clone());
+                    }
+                }
+            }
+            ControlSignal::Announce(inventory) => {
+                trace!("Processing Announce command");
+                for peer_id in &self.peer_list {
+                    let peer = &self.peers[*peer_id];
+                    if !peer.is_ready() {
+                        continue;
+                    }
+                    // tell each peer only about what it lacks
+                    match &inventory {
+                        Inventory::Blocks(hashes) => {
+                            let hashes = peer.handle.unknown(hashes);
+                            if !hashes.is_empty() {
+                                peer.handle.write(Message::NewBLockHashes(hashes));
+                            }
+                        }
+                        Inventory::Transactions(hashes) => {
+                            let hashes = peer.handle.unknown(hashes);
+                            if !hashes.is_empty() {
+                                peer.handle.write(Message::NewTransactionHashes(hashes));
+                            }
+                        }
+                    }
+                }
+            }
+            ControlSignal::ListPeers(result_chan) => {
+                trace!("Processing ListPeers command");
+                let peers = self
+                    .peer_list
+                    .iter()
+                    .map(|peer_id| {
+                        let peer = &self.peers[*peer_id];
+                        PeerInfo {
+                            addr: peer.addr,
+                            direction: peer.direction,
+                            listen_addr: peer.version.as_ref().map(|v| v.listen_addr),
+                            best_height: peer.version.as_ref().map(|_| peer.handle.best_height()),
+                            ready: peer.is_ready(),
+                            peer_id: peer.peer_id.map(|id| id.to_string()),
+                            handle: peer.handle.clone(),
+                        }
+                    })
+                    .collect();
+                result_chan.send(peers).unwrap();
+            }
+            ControlSignal::Report(addr, offence) => {
+                trace!("Processing Report command");
+                self.report(addr, offence);
+            }
+            ControlSignal::Disconnect(addr) => {
+                trace!("Processing Disconnect command");
+                if let Some(&peer_id) = self.peer_list.iter().find(|&&id| self.peers[id].addr == addr) {
+                    info!("Disconnecting peer {}", addr);
+                    self.disconnect(peer_id);
                 }
             }
         }
         Ok(())
     }
 
+    /// Add to the misbehaviour score of a peer, banning it once the score gets too high.
+    fn report(&mut self, addr: std::net::SocketAddr, offence: Misbehaviour) {
+        // the peer may be gone already
+        if let Some(&peer_id) = self.peer_list.iter().find(|&&id| self.peers[id].addr == addr) {
+            self.punish(peer_id, offence);
+        }
+    }
+
+    /// Add `offence` to the score of the peer, which outlives the connection. Once the score
+    /// reaches `BAN_THRESHOLD` the peer is banned and dropped, and `true` is returned.
+    fn punish(&mut self, peer_id: usize, offence: Misbehaviour) -> bool {
+        let peer = &self.peers[peer_id];
+        let (addr, offender, listen_addr) = (peer.addr, peer.offender(), peer.listen_addr());
+        let score = self.bans.add(offender, offence);
+        warn!("Peer {} misbehaved: {}, score {}", addr, offence, score);
+        if score < BAN_THRESHOLD {
+            return false;
+        }
+        warn!("Banning peer {} ({:?}) for {:?}", addr, offender, BAN_DURATION);
+        self.bans.ban(offender, BAN_DURATION);
+        // and its address, so we don't dial it either. Other nodes on the same host are fine.
+        self.bans.ban(Offender::Addr(listen_addr), BAN_DURATION);
+        self.addrbook.lock().unwrap().remove(&listen_addr);
+        self.disconnect(peer_id);
+        true
+    }
+
+    /// Drop the peers that did not complete the handshake within `HANDSHAKE_TIMEOUT`, so they
+    /// don't hold a slot forever.
+    fn drop_stuck_handshakes(&mut self) {
+        let now = std::time::Instant::now();
+        let stuck: Vec<usize> = self
+            .peer_list
+            .iter()
+            .copied()
+            .filter(|&id| {
+                let peer = &self.peers[id];
+                !peer.is_ready() && now.duration_since(peer.connected_at) >= HANDSHAKE_TIMEOUT
+            })
+            .collect();
+        for peer_id in stuck {
+            let addr = self.peers[peer_id].addr;
+            info!("Peer {} did not complete the handshake in time, disconnecting", addr);
+            self.disconnect(peer_id);
+        }
+    }
+
+    /// Drop the connection to a peer.
+    fn disconnect(&mut self, peer_id: usize) {
+        self.peers.remove(peer_id);
+        let index = self.peer_list.iter().position(|&x| x == peer_id).unwrap();
+        self.peer_list.swap_remove(index);
+    }
+
     fn register_write_interest(&mut self, peer_id: usize) -> std::io::Result<()> {
         trace!("Registering socket write interest for peer {}", peer_id);
         let peer = &mut self.peers[peer_id];
@@ -174,10 +339,93 @@ impl Context {
                     // no full message has been received
                     continue;
                 }
+                Ok(ReadResult::Session(id, sealing)) => {
+                    if self.node_key.as_ref().map(|key| key.id()) == Some(id) {
+                        warn!("Peer {} holds our own node key, disconnecting", peer.addr);
+                        // never try our own address again
+                        self.addrbook.lock().unwrap().remove(&peer.addr);
+                        self.disconnect(peer_id);
+                        break;
+                    }
+                    if self.bans.is_banned(&Offender::Node(id)) {
+                        info!("Refusing banned node {} at {}", id, peer.addr);
+                        self.disconnect(peer_id);
+                        break;
+                    }
+                    info!("Encrypted session with peer {} set up, peer ID {}", peer.addr, id);
+                    peer.peer_id = Some(id);
+                    peer.writer.start_session(sealing);
+                    // the frames queued during the session handshake can go out now
+                    self.poll.reregister(
+                        &peer.stream,
+                        mio::Token(peer_id * 2),
+                        mio::Ready::readable() | mio::Ready::writable(),
+                        mio::PollOpt::edge(),
+                    )?;
+                    continue;
+                }
                 Ok(ReadResult::Message(m)) => {
                     trace!("Peer {} yield message", peer_id);
                     // we just received a full message
-                    self.new_msg_chan.send((m, peer.handle.clone())).unwrap();
+                    if peer.is_ready() {
+                        self.new_msg_chan.send((m, peer.handle.clone())).unwrap();
+                        continue;
+                    }
+                    match m.message() {
+                        Ok(Message::Version(ref version))
+                            if self.bans.is_banned(&Offender::Addr(version.listen_addr)) =>
+                        {
+                            info!("Refusing banned peer {} at {}", version.listen_addr, peer.addr);
+                            self.disconnect(peer_id);
+                            break;
+                        }
+                        Ok(Message::Version(version)) => match self.local.check(&version) {
+                            Ok(()) => {
+                                peer.handle.saw_height(version.best_height);
+                                peer.version = Some(version);
+                                peer.handle.write(Message::Verack);
+                            }
+                            Err(e) => {
+                                warn!("Handshake with peer {} failed, disconnecting: {}", peer.addr, e);
+                                if e == HandshakeError::SelfConnection {
+                                    // never try our own address again
+                                    let mut addrbook = self.addrbook.lock().unwrap();
+                                    addrbook.remove(&version.listen_addr);
+                                    addrbook.remove(&peer.addr);
+                                }
+                                self.disconnect(peer_id);
+                                break;
+                            }
+                        },
+                        Ok(Message::Verack) => {
+                            peer.verack_received = true;
+                        }
+                        Ok(_) => {
+                            debug!("Ignoring message from peer {} before the handshake", peer.addr);
+                        }
+                        Err(e) => {
+                            warn!("Undecodable handshake from peer {}, disconnecting: {}", peer.addr, e);
+                            if !self.punish(peer_id, Misbehaviour::MalformedMessage) {
+                                self.disconnect(peer_id);
+                            }
+                            break;
+                        }
+                    }
+                    if let (true, Some(version)) = (peer.is_ready(), &peer.version) {
+                        info!(
+                            "Handshake with peer {} complete, protocol version {}, best height {}",
+                            peer.addr, version.protocol_version, version.best_height
+                        );
+                        // the address the peer listens on, not the port it connected from
+                        self.addrbook.lock().unwrap().mark_success(version.listen_addr);
+                        if let peer::Direction::Outgoing = peer.direction {
+                            peer.handle.write(Message::GetAddr);
+                        }
+                        // catch up with peers ahead of us, headers first
+                        if let Some(locator) = self.local.locator_if_behind(version) {
+                            peer.handle.write(Message::GetHeaders(locator));
+                        }
+                    }
                     continue;
                 }
                 Err(e) => {
@@ -187,9 +435,14 @@ impl Context {
                         break;
                     } else {
                         warn!("Error reading peer {}, disconnecting: {}", peer.addr, e);
-                        self.peers.remove(peer_id);
-                        let index = self.peer_list.iter().position(|&x| x == peer_id).unwrap();
-                        self.peer_list.swap_remove(index);
+                        // breaking the framing counts against the peer
+                        let banned = match frame_offence(&e) {
+                            Some(offence) => self.punish(peer_id, offence),
+                            None => false,
+                        };
+                        if !banned {
+                            self.disconnect(peer_id);
+                        }
                         break;
                     }
                 }
@@ -284,7 +537,8 @@ impl Context {
         let mut events = mio::Events::with_capacity(MAX_EVENT);
 
         loop {
-            self.poll.poll(&mut events, None)?;
+            self.poll.poll(&mut events, Some(HANDSHAKE_CHECK_INTERVAL))?;
+            self.drop_stuck_handshakes();
 
             for event in events.iter() {
                 match event.token() {
@@ -353,6 +607,10 @@ impl Context {
                             }
                             1 => {
                                 trace!("Peer {} outgoing queue readable", peer_id);
+                                // the peer may have been dropped, e.g. by a failed handshake
+                                if !self.peers.contains(peer_id) {
+                                    continue;
+                                }
                                 self.register_write_interest(peer_id)?;
                             }
                             _ => unreachable!(),
@@ -364,16 +622,29 @@ impl Context {
     }
 }
 
+/// The misbehaviour behind a read error, if the peer broke the framing
+fn frame_offence(e: &std::io::Error) -> Option<Misbehaviour> {
+    match e.get_ref()?.downcast_ref::<FrameError>()? {
+        FrameError::Oversized(_) => Some(Misbehaviour::OversizedMessage),
+        _ => Some(Misbehaviour::MalformedMessage),
+    }
+}
+
 #[derive(Clone)]
 pub struct Handle {
     control_chan: channel::Sender<ControlSignal>,
 }
 
 impl Handle {
+    /// Dial `addr` and add it as an outgoing peer. Blocks the calling thread for up to
+    /// `CONNECT_TIMEOUT`.
     pub fn connect(&self, addr: std::net::SocketAddr) -> std::io::Result<peer::Handle> {
+        // dial here, so a dead address never stalls the I/O of the other peers
+        let stream = std::net::TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT)?;
         let (sender, receiver) = cbchannel::unbounded();
         let request = ConnectRequest {
             addr,
+            stream,
             result_chan: sender,
         };
         self.control_chan
@@ -387,14 +658,114 @@ impl Handle {
             .send(ControlSignal::BroadcastMessage(msg))
             .unwrap();
     }
+
+    /// Announce new blocks or transactions to the peers that don't have them yet.
+    pub fn announce(&self, inventory: Inventory) {
+        self.control_chan
+            .send(ControlSignal::Announce(inventory))
+            .unwrap();
+    }
+
+    /// Add `offence` to the misbehaviour score of `peer`.
+    pub fn report(&self, peer: &peer::Handle, offence: Misbehaviour) {
+        self.control_chan
+            .send(ControlSignal::Report(peer.addr(), offence))
+            .unwrap();
+    }
+
+    /// Drop the connection to `peer`, e.g. after it broke the framing.
+    pub fn disconnect(&self, peer: &peer::Handle) {
+        self.control_chan
+            .send(ControlSignal::Disconnect(peer.addr()))
+            .unwrap();
+    }
+
+    /// The peers currently connected, including those still in the handshake.
+    pub fn peers(&self) -> Vec<PeerInfo> {
+        let (sender, receiver) = cbchannel::unbounded();
+        self.control_chan
+            .send(ControlSignal::ListPeers(sender))
+            .unwrap();
+        receiver.recv().unwrap()
+    }
+}
+
+/// A connected peer as seen by the server
+#[derive(Serialize, Clone)]
+pub struct PeerInfo {
+    /// The address of the socket, for outgoing peers the address we dialed
+    pub addr: std::net::SocketAddr,
+    pub direction: peer::Direction,
+    /// Where the peer accepts connections, known once it sent its version
+    pub listen_addr: Option<std::net::SocketAddr>,
+    /// Height of the peer's best block, from its version and the blocks it announced since
+    pub best_height: Option<u32>,
+    /// Whether the handshake completed
+    pub ready: bool,
+    /// The node key the peer proved to hold, in encrypted sessions
+    pub peer_id: Option<String>,
+    #[serde(skip)]
+    pub handle: peer::Handle,
 }
 
 enum ControlSignal {
     ConnectNewPeer(ConnectRequest),
     BroadcastMessage(message::Message),
+    Announce(Inventory),
+    ListPeers(cbchannel::Sender<Vec<PeerInfo>>),
+    Report(std::net::SocketAddr, Misbehaviour),
+    Disconnect(std::net::SocketAddr),
 }
 
 struct ConnectRequest {
     addr: std::net::SocketAddr,
+    stream: std::net::TcpStream,
     result_chan: cbchannel::Sender<std::io::Result<peer::Handle>>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::Blockchain;
+    use crate::params::ChainParams;
+    use std::io::{Read, Write};
+
+    #[test]
+    fn bad_frames_get_a_peer_banned_across_connections() {
+        let (msg_sender, _msg_receiver) = cbchannel::unbounded();
+        let params = ChainParams::regtest();
+        let frame_config = frame::Config::new(&params);
+        let blockchain = Arc::new(Mutex::new(Blockchain::with_params(params)));
+        let addr = "127.0.0.1:0".parse().unwrap();
+        let local = Local::new(&blockchain, addr);
+        let addrbook = Arc::new(Mutex::new(AddressBook::new()));
+        let (server_ctx, server) =
+            new(addr, msg_sender, frame_config, None, local, &addrbook).unwrap();
+        server_ctx.start().unwrap();
+
+        let bad = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let bad_addr = bad.local_addr().unwrap();
+        let neighbour = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        addrbook.lock().unwrap().add(bad_addr, 0);
+        addrbook.lock().unwrap().add(neighbour.local_addr().unwrap(), 0);
+        // a frame of our network whose payload does not match its checksum
+        let mut garbage = frame_config.magic.to_vec();
+        garbage.extend_from_slice(&[0, 0, 0, 0, 1, 0, 0, 0, 0, 0]);
+        let strikes = BAN_THRESHOLD / Misbehaviour::MalformedMessage.score();
+        for _ in 0..strikes {
+            server.connect(bad_addr).unwrap();
+            let (mut stream, _) = bad.accept().unwrap();
+            stream.set_read_timeout(Some(std::time::Duration::from_secs(5))).unwrap();
+            stream.write_all(&garbage).unwrap();
+            // the server hangs up, and the score stays when we come back
+            let mut rest = Vec::new();
+            stream.read_to_end(&mut rest).unwrap();
+        }
+        let refused = server.connect(bad_addr).err().unwrap();
+        assert_eq!(refused.kind(), std::io::ErrorKind::PermissionDenied);
+        assert!(addrbook.lock().unwrap().get(&bad_addr).is_none());
+        // the other node on the same host is still welcome
+        assert!(addrbook.lock().unwrap().get(&neighbour.local_addr().unwrap()).is_some());
+        server.connect(neighbour.local_addr().unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,294 @@
+use crate::crypto::hash::H160;
+use crate::transaction::public_key_to_address;
+use ring::rand::SystemRandom;
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use ring::{aead, agreement, hkdf, signature};
+use std::convert::TryInto;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+/// Opens every hello, so a peer without encryption is told apart from a forged hello
+const MARKER: [u8; 4] = *b"SEC1";
+const X25519_KEY_LEN: usize = 32;
+const ED25519_KEY_LEN: usize = 32;
+const SIGNATURE_LEN: usize = 64;
+/// Size of the hello each side sends first: marker, network magic, ephemeral key, node key and
+/// the signature of everything before it
+pub const HELLO_LEN: usize = 4 + 4 + X25519_KEY_LEN + ED25519_KEY_LEN + SIGNATURE_LEN;
+/// Size of the length in front of every record
+pub const RECORD_HEADER_LEN: usize = 4;
+/// Authentication tag appended to every record
+pub const TAG_LEN: usize = 16;
+
+/// Ways setting up or using a session can fail.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum SessionError {
+    /// The peer does not speak the encrypted transport
+    NotEncrypted,
+    BadMagic([u8; 4]),
+    BadSignature,
+    KeyAgreement,
+    /// A record failed authentication, it was tampered with or sent out of order
+    BadRecord,
+    Oversized(usize),
+}
+
+impl std::fmt::Display for SessionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            SessionError::NotEncrypted => write!(f, "peer did not open an encrypted session"),
+            SessionError::BadMagic(magic) => write!(f, "wrong network magic {}", hex::encode(magic)),
+            SessionError::BadSignature => write!(f, "hello not signed by the node key"),
+            SessionError::KeyAgreement => write!(f, "key agreement failed"),
+            SessionError::BadRecord => write!(f, "record failed authentication"),
+            SessionError::Oversized(len) => write!(f, "record of {} bytes over the limit", len),
+        }
+    }
+}
+
+impl std::error::Error for SessionError {}
+
+impl From<SessionError> for std::io::Error {
+    fn from(e: SessionError) -> Self {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, e)
+    }
+}
+
+/// The long-lived identity of a node, which signs the hellos of its sessions.
+pub struct NodeKey {
+    pair: Ed25519KeyPair,
+}
+
+impl NodeKey {
+    /// A key for this run only.
+    pub fn random() -> Self {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        NodeKey {
+            pair: Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap(),
+        }
+    }
+
+    /// Read the key stored at `path`, creating it first if there is none.
+    pub fn load_or_create(path: &Path) -> std::io::Result<Self> {
+        let invalid = |_| std::io::Error::new(std::io::ErrorKind::InvalidData, "invalid node key");
+        if path.exists() {
+            let pkcs8 = fs::read(path)?;
+            let pair = Ed25519KeyPair::from_pkcs8(&pkcs8).map_err(invalid)?;
+            return Ok(NodeKey { pair });
+        }
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        // written aside and renamed, so a crash never leaves half a key behind
+        let tmp_path = path.with_extension("tmp");
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            // only the node should read its identity
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut file = options.open(&tmp_path)?;
+        file.write_all(pkcs8.as_ref())?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, path)?;
+        let pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).map_err(invalid)?;
+        Ok(NodeKey { pair })
+    }
+
+    pub fn id(&self) -> H160 {
+        peer_id(self.pair.public_key().as_ref())
+    }
+}
+
+/// The ID of the node with the public key `public_key`
+pub fn peer_id(public_key: &[u8]) -> H160 {
+    public_key_to_address(public_key).into()
+}
+
+/// Our half of a session being set up. Both sides send their hello, the session starts once
+/// the hello of the peer is checked.
+pub struct Handshake {
+    ephemeral: agreement::EphemeralPrivateKey,
+    hello: Vec<u8>,
+    magic: [u8; 4],
+    /// Whether we dialed the peer, which decides which key encrypts which direction
+    initiator: bool,
+}
+
+impl Handshake {
+    pub fn new(node_key: &NodeKey, magic: [u8; 4], initiator: bool) -> Self {
+        let rng = SystemRandom::new();
+        let ephemeral = agreement::EphemeralPrivateKey::generate(&agreement::X25519, &rng).unwrap();
+        let mut hello = Vec::with_capacity(HELLO_LEN);
+        hello.extend_from_slice(&MARKER);
+        hello.extend_from_slice(&magic);
+        hello.extend_from_slice(ephemeral.compute_public_key().unwrap().as_ref());
+        hello.extend_from_slice(node_key.pair.public_key().as_ref());
+        let signature = node_key.pair.sign(&hello);
+        hello.extend_from_slice(signature.as_ref());
+        Handshake {
+            ephemeral,
+            hello,
+            magic,
+            initiator,
+        }
+    }
+
+    /// What to send the peer before anything else
+    pub fn hello(&self) -> &[u8] {
+        &self.hello
+    }
+
+    /// Check the hello of the peer and derive the session keys from both hellos. Returns the
+    /// ID of the peer, which only the holder of its node key can claim.
+    pub fn finish(self, peer_hello: &[u8; HELLO_LEN]) -> Result<(H160, Opening, Sealing), SessionError> {
+        if peer_hello[0..4] != MARKER {
+            return Err(SessionError::NotEncrypted);
+        }
+        let magic: [u8; 4] = peer_hello[4..8].try_into().unwrap();
+        if magic != self.magic {
+            return Err(SessionError::BadMagic(magic));
+        }
+        let (signed, signature) = peer_hello.split_at(HELLO_LEN - SIGNATURE_LEN);
+        let ephemeral_key = &signed[8..8 + X25519_KEY_LEN];
+        let node_key = &signed[8 + X25519_KEY_LEN..];
+        signature::UnparsedPublicKey::new(&signature::ED25519, node_key)
+            .verify(signed, signature)
+            .map_err(|_| SessionError::BadSignature)?;
+
+        // bind the keys to both hellos, so neither can be swapped out
+        let mut transcript = Vec::with_capacity(2 * HELLO_LEN);
+        if self.initiator {
+            transcript.extend_from_slice(&self.hello);
+            transcript.extend_from_slice(peer_hello);
+        } else {
+            transcript.extend_from_slice(peer_hello);
+            transcript.extend_from_slice(&self.hello);
+        }
+        let initiator = self.initiator;
+        let peer_ephemeral = agreement::UnparsedPublicKey::new(&agreement::X25519, ephemeral_key);
+        let (to_responder, to_initiator) = agreement::agree_ephemeral(
+            self.ephemeral,
+            &peer_ephemeral,
+            SessionError::KeyAgreement,
+            |shared| {
+                let prk = hkdf::Salt::new(hkdf::HKDF_SHA256, &transcript).extract(shared);
+                let key = |info: &[u8]| -> Result<aead::LessSafeKey, SessionError> {
+                    let info = [info];
+                    let okm = prk
+                        .expand(&info, &aead::CHACHA20_POLY1305)
+                        .map_err(|_| SessionError::KeyAgreement)?;
+                    Ok(aead::LessSafeKey::new(okm.into()))
+                };
+                Ok((key(b"initiator")?, key(b"responder")?))
+            },
+        )?;
+        let (open_key, seal_key) = if initiator {
+            (to_initiator, to_responder)
+        } else {
+            (to_responder, to_initiator)
+        };
+        Ok((
+            peer_id(node_key),
+            Opening { key: open_key, counter: 0 },
+            Sealing { key: seal_key, counter: 0 },
+        ))
+    }
+}
+
+/// Records are numbered per direction, so a replayed or dropped record fails to open
+fn nonce(counter: u64) -> aead::Nonce {
+    let mut nonce = [0; aead::NONCE_LEN];
+    nonce[4..].copy_from_slice(&counter.to_be_bytes());
+    aead::Nonce::assume_unique_for_key(nonce)
+}
+
+/// Encrypts what we send in a session.
+pub struct Sealing {
+    key: aead::LessSafeKey,
+    counter: u64,
+}
+
+impl Sealing {
+    /// Encrypt `frame` into a record: its length, then the ciphertext and tag.
+    pub fn seal(&mut self, frame: &[u8]) -> Vec<u8> {
+        let mut record = Vec::with_capacity(RECORD_HEADER_LEN + frame.len() + TAG_LEN);
+        record.extend_from_slice(&((frame.len() + TAG_LEN) as u32).to_be_bytes());
+        record.extend_from_slice(frame);
+        let tag = self
+            .key
+            .seal_in_place_separate_tag(nonce(self.counter), aead::Aad::empty(), &mut record[RECORD_HEADER_LEN..])
+            .unwrap();
+        record.extend_from_slice(tag.as_ref());
+        self.counter += 1;
+        record
+    }
+}
+
+/// Decrypts what the peer sends in a session.
+pub struct Opening {
+    key: aead::LessSafeKey,
+    counter: u64,
+}
+
+impl Opening {
+    /// Decrypt the body of a record in place, returning the plaintext.
+    pub fn open<'a>(&mut self, body: &'a mut [u8]) -> Result<&'a [u8], SessionError> {
+        let plain = self
+            .key
+            .open_in_place(nonce(self.counter), aead::Aad::empty(), body)
+            .map_err(|_| SessionError::BadRecord)?;
+        self.counter += 1;
+        Ok(plain)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn body(record: &[u8]) -> Vec<u8> {
+        let len = u32::from_be_bytes(record[..RECORD_HEADER_LEN].try_into().unwrap()) as usize;
+        assert_eq!(record.len(), RECORD_HEADER_LEN + len);
+        record[RECORD_HEADER_LEN..].to_vec()
+    }
+
+    #[test]
+    fn peers_agree_on_keys_and_reject_tampering() {
+        let magic = [1, 2, 3, 4];
+        let (alice_key, bob_key) = (NodeKey::random(), NodeKey::random());
+        let alice = Handshake::new(&alice_key, magic, true);
+        let bob = Handshake::new(&bob_key, magic, false);
+        let alice_hello: [u8; HELLO_LEN] = alice.hello().try_into().unwrap();
+        let bob_hello: [u8; HELLO_LEN] = bob.hello().try_into().unwrap();
+
+        // a forged node key or another network is refused
+        let mut forged = bob_hello;
+        forged[8 + X25519_KEY_LEN] ^= 1;
+        assert_eq!(
+            Handshake::new(&alice_key, magic, true).finish(&forged).err(),
+            Some(SessionError::BadSignature)
+        );
+        assert_eq!(
+            Handshake::new(&alice_key, [0; 4], true).finish(&bob_hello).err(),
+            Some(SessionError::BadMagic(magic))
+        );
+
+        let (bob_id, mut alice_open, mut alice_seal) = alice.finish(&bob_hello).unwrap();
+        let (alice_id, mut bob_open, mut bob_seal) = bob.finish(&alice_hello).unwrap();
+        assert_eq!(bob_id, bob_key.id());
+        assert_eq!(alice_id, alice_key.id());
+
+        let record = body(&alice_seal.seal(b"block"));
+        assert_eq!(bob_open.open(&mut record.clone()).unwrap(), b"block");
+        let mut reply = body(&bob_seal.seal(b"verack"));
+        assert_eq!(alice_open.open(&mut reply).unwrap(), b"verack");
+
+        // a replayed record or a flipped bit does not open
+        assert_eq!(bob_open.open(&mut record.clone()).err(), Some(SessionError::BadRecord));
+        let mut tampered = body(&alice_seal.seal(b"tx"));
+        tampered[0] ^= 1;
+        assert_eq!(bob_open.open(&mut tampered).err(), Some(SessionError::BadRecord));
+    }
+}
@@ -1,39 +1,190 @@
+use super::compact::{CompactBlock, PartialBlock};
+use super::download::{Downloads, BLOCK_TIMEOUT, MAX_IN_FLIGHT_PER_PEER, STALL_BACKOFF};
+use super::frame::Frame;
+use super::inventory::Inventory;
 use super::message::Message;
+use super::misbehaviour::Misbehaviour;
 use super::peer;
-use crate::network::server::Handle as ServerHandle;
-use crate::crypto::hash:: Hashable;
+use crate::network::server::{Handle as ServerHandle, PeerInfo};
+use crate::miner::{accept_block, merkle_root, Handle as MinerHandle};
+use crate::crypto::hash::{H256, Hashable};
 use crossbeam::channel;
 use log::{debug, warn};
 use std::sync::{Arc, Mutex};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
 use crate::blockchain::*;
 use crate::block::*;
 use crate::transaction::*;
+use crate::mempool::Mempool;
+use super::addrbook::{AddressBook, MAX_ADDR_PER_MESSAGE};
 use std::thread;
 use log::info;
 
+/// Most headers in one `Headers` message
+const MAX_HEADERS: usize = 2000;
+/// Most blocks asked from one peer in one `GetBlocks`
+const BLOCKS_PER_REQUEST: usize = 16;
+/// Most blocks ahead of our tip being downloaded at the same time
+const MAX_BLOCKS_IN_FLIGHT: usize = 256;
+/// How often stalled block requests are looked for
+const DOWNLOAD_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// Most blocks kept while waiting for their parent
+const MAX_ORPHANS: usize = 4096;
+/// Most compact blocks waiting for missing transactions
+const MAX_PARTIAL_BLOCKS: usize = 64;
+/// Room left in a frame for the message type and list length around the blocks of a reply
+const MESSAGE_OVERHEAD: usize = 64;
+
 #[derive(Clone)]
 pub struct Context {
-    msg_chan: channel::Receiver<(Vec<u8>, peer::Handle)>,
+    msg_chan: channel::Receiver<(Frame, peer::Handle)>,
     num_worker: usize,
     server: ServerHandle,
     blockchain: Arc<Mutex<Blockchain>>,
     mempool: Arc<Mutex<Mempool>>,
+    miner: MinerHandle,
+    addrbook: Arc<Mutex<AddressBook>>,
+    // blocks waiting for their parent, locked after the blockchain
+    orphans: Arc<Mutex<Orphans>>,
+    // blocks asked for, locked after the orphans and before the mempool
+    downloads: Arc<Mutex<Downloads>>,
+    // compact blocks waiting for the transactions we lacked
+    partial_blocks: Arc<Mutex<PartialBlocks>>,
+}
+
+/// Blocks received before their parent, with the peer that sent each
+#[derive(Default)]
+struct Orphans {
+    // key:parent_hash value:child_hashes
+    children: HashMap<H256, Vec<H256>>,
+    // key:child_hash value:child_block and its sender
+    blocks: HashMap<H256, (Block, peer::Handle)>,
+    // child hashes, oldest first, some of them connected already
+    order: VecDeque<H256>,
+}
+
+impl Orphans {
+    fn contains(&self, hash: &H256) -> bool {
+        self.blocks.contains_key(hash)
+    }
+
+    /// Keep `block` until its parent arrives, dropping the oldest orphans when full.
+    fn insert(&mut self, block: Block, from: &peer::Handle) {
+        let hash = block.hash();
+        if self.blocks.contains_key(&hash) {
+            return;
+        }
+        while self.blocks.len() >= MAX_ORPHANS {
+            match self.order.pop_front() {
+                Some(oldest) => self.remove(&oldest),
+                None => break,
+            }
+        }
+        if self.order.len() >= 2 * MAX_ORPHANS {
+            let blocks = &self.blocks;
+            self.order.retain(|hash| blocks.contains_key(hash));
+        }
+        self.children.entry(block.header.parent).or_default().push(hash);
+        self.blocks.insert(hash, (block, from.clone()));
+        self.order.push_back(hash);
+    }
+
+    fn remove(&mut self, hash: &H256) {
+        if let Some((block, _)) = self.blocks.remove(hash) {
+            let parent = block.header.parent;
+            if let Some(siblings) = self.children.get_mut(&parent) {
+                siblings.retain(|sibling| sibling != hash);
+                if siblings.is_empty() {
+                    self.children.remove(&parent);
+                }
+            }
+        }
+    }
+
+    /// Remove and return the blocks waiting for `parent`, with their senders
+    fn take_children(&mut self, parent: &H256) -> Vec<(Block, peer::Handle)> {
+        let hashes = self.children.remove(parent).unwrap_or_default();
+        hashes.iter().filter_map(|hash| self.blocks.remove(hash)).collect()
+    }
+}
+
+/// Insert a block whose parent we have, updating the state and the mempool. A block with an
+/// invalid transaction is dropped with its header.
+fn connect_block(block: &Block, blc: &mut Blockchain, mempool: &mut Mempool) -> bool {
+    if accept_block(block, blc, mempool) {
+        return true;
+    }
+    warn!("Ignoring block {} with an invalid transaction", block.hash());
+    blc.discard_header(block.hash());
+    false
+}
+
+/// Raise the best height we know `peer` has to that of the highest of `hashes` whose header we
+/// have, so block downloads can be spread over every peer that caught up since it connected.
+fn saw_blocks(peer: &peer::Handle, blc: &Blockchain, hashes: impl IntoIterator<Item = H256>) {
+    let heights = hashes.into_iter().filter_map(|hash| blc.headers.get(&hash));
+    if let Some(height) = heights.map(|(_, height)| *height).max() {
+        peer.saw_height(height);
+    }
+}
+
+/// Compact blocks waiting for the transactions we asked a peer for, keyed by block and peer
+#[derive(Default)]
+struct PartialBlocks {
+    blocks: HashMap<(H256, SocketAddr), PartialBlock>,
+    // keys, oldest first, some of them answered already
+    order: VecDeque<(H256, SocketAddr)>,
+}
+
+impl PartialBlocks {
+    /// Wait for `peer` to send the transactions of `partial`, dropping the oldest requests when
+    /// full. Those blocks are fetched in full once their download times out.
+    fn insert(&mut self, hash: H256, peer: SocketAddr, partial: PartialBlock) {
+        while self.blocks.len() >= MAX_PARTIAL_BLOCKS {
+            match self.order.pop_front() {
+                Some(oldest) => {
+                    self.blocks.remove(&oldest);
+                }
+                None => break,
+            }
+        }
+        if self.order.len() >= 2 * MAX_PARTIAL_BLOCKS {
+            let blocks = &self.blocks;
+            self.order.retain(|key| blocks.contains_key(key));
+        }
+        if self.blocks.insert((hash, peer), partial).is_none() {
+            self.order.push_back((hash, peer));
+        }
+    }
+
+    /// The block waiting for transactions from `peer`, `None` if we did not ask it
+    fn take(&mut self, hash: H256, peer: SocketAddr) -> Option<PartialBlock> {
+        self.blocks.remove(&(hash, peer))
+    }
 }
 
 pub fn new(
     num_worker: usize,
-    msg_src: channel::Receiver<(Vec<u8>, peer::Handle)>,
+    msg_src: channel::Receiver<(Frame, peer::Handle)>,
     server: &ServerHandle,
     blockchain: &Arc<Mutex<Blockchain>>,
     mempool: &Arc<Mutex<Mempool>>,
+    miner: &MinerHandle,
+    addrbook: &Arc<Mutex<AddressBook>>,
 ) -> Context {
     Context {
         msg_chan: msg_src,
         num_worker,
         server: server.clone(),
         blockchain: Arc::clone(blockchain),
-        mempool: Arc::clone(mempool)
+        mempool: Arc::clone(mempool),
+        miner: miner.clone(),
+        addrbook: Arc::clone(addrbook),
+        orphans: Arc::new(Mutex::new(Orphans::default())),
+        downloads: Arc::new(Mutex::new(Downloads::new())),
+        partial_blocks: Arc::new(Mutex::new(PartialBlocks::default())),
     }
 }
 
@@ -47,19 +198,266 @@ impl Context {
                 warn!("Worker thread {} exited", i);
             });
         }
+        thread::Builder::new()
+            .name("block-download".to_string())
+            .spawn(move || loop {
+                thread::sleep(DOWNLOAD_CHECK_INTERVAL);
+                self.retry_downloads();
+            })
+            .unwrap();
+    }
+
+    /// Keep the lowest blocks missing on the way to the best header requested, in chunks spread
+    /// over the peers that have them, avoiding those that stalled recently.
+    fn request_blocks(&self, from: Option<&peer::Handle>) {
+        let blc = self.blockchain.lock().unwrap();
+        let orphans = self.orphans.lock().unwrap();
+        let mut downloads = self.downloads.lock().unwrap();
+        let wanted: Vec<(H256, u32)> = blc
+            .missing_blocks(MAX_BLOCKS_IN_FLIGHT)
+            .into_iter()
+            .filter(|hash| !orphans.contains(hash) && !downloads.is_requested(hash))
+            .map(|hash| (hash, blc.headers[&hash].1))
+            .collect();
+        drop(orphans);
+        drop(blc);
+        if wanted.is_empty() {
+            return;
+        }
+        let peers: Vec<PeerInfo> = self
+            .server
+            .peers()
+            .into_iter()
+            .filter(|p| p.ready)
+            .collect();
+        let now = Instant::now();
+        for (i, chunk) in wanted.chunks(BLOCKS_PER_REQUEST).enumerate() {
+            let (_, height) = chunk[chunk.len() - 1];
+            let mut able: Vec<&peer::Handle> = peers
+                .iter()
+                .filter(|p| p.best_height.is_some_and(|h| h >= height))
+                .filter(|p| from.is_none_or(|from| p.addr != from.addr()))
+                .map(|p| &p.handle)
+                .collect();
+            // the peer that sent the headers or blocks has the blocks for sure
+            if let Some(from) = from {
+                able.push(from);
+            }
+            able.retain(|p| downloads.in_flight(&p.addr()) + chunk.len() <= MAX_IN_FLIGHT_PER_PEER);
+            // peers that let requests time out are left alone for a while, unless nobody else
+            // has the blocks
+            if able.iter().any(|p| !downloads.is_stalling(&p.addr(), now)) {
+                able.retain(|p| !downloads.is_stalling(&p.addr(), now));
+            }
+            if able.is_empty() {
+                // everyone is busy, the rest is asked for once blocks arrive
+                break;
+            }
+            let target = able[i % able.len()];
+            let hashes: Vec<H256> = chunk.iter().map(|(hash, _)| *hash).collect();
+            let hashes = downloads.start(target.addr(), &hashes, now);
+            target.write(Message::GetBlocks(hashes));
+        }
+    }
+
+    /// Take back the block requests that timed out or whose peer went away, and ask someone else.
+    fn retry_downloads(&self) {
+        let connected: HashSet<SocketAddr> = self
+            .server
+            .peers()
+            .into_iter()
+            .filter(|p| p.ready)
+            .map(|p| p.addr)
+            .collect();
+        let stalled = self
+            .downloads
+            .lock()
+            .unwrap()
+            .stalled(&connected, Instant::now(), BLOCK_TIMEOUT);
+        let mut slow = HashSet::new();
+        for (hash, addr) in &stalled {
+            debug!("Request for block {} to peer {} stalled", hash, addr);
+            if connected.contains(addr) && slow.insert(*addr) {
+                warn!("Peer {} is too slow delivering blocks, asking other peers for {:?}", addr, STALL_BACKOFF);
+            }
+        }
+        self.request_blocks(None);
+    }
+
+    /// Rebuild a compact block from the mempool, asking the peer for the transactions we lack.
+    fn accept_compact_block(&self, compact: CompactBlock, peer: &peer::Handle, delay_list: &mut Vec<u128>) {
+        let hash = compact.header.hash();
+        peer.mark_known(&[hash]);
+        let mut blc = self.blockchain.lock().unwrap();
+        if blc.blocks.contains_key(&hash) {
+            return;
+        }
+        if compact.short_ids.len() > blc.params.max_block_txs {
+            warn!("Ignoring compact block {} over the block limits", hash);
+            self.server.report(peer, Misbehaviour::InvalidBlock);
+            return;
+        }
+        match blc.insert_header(&compact.header) {
+            Ok(_) => saw_blocks(peer, &blc, vec![hash]),
+            Err(HeaderError::InvalidProofOfWork(_)) => {
+                self.server.report(peer, Misbehaviour::InvalidProofOfWork);
+                return;
+            }
+            Err(HeaderError::UnknownParent(_)) => {
+                // we are missing part of the chain, ask for the headers leading here. The block
+                // is fetched in full once they arrive, the peer did deliver this request
+                self.downloads.lock().unwrap().received(&hash);
+                let locator = blc.locator();
+                drop(blc);
+                peer.write(Message::GetHeaders(locator));
+                return;
+            }
+        }
+        drop(blc);
+        let partial = compact.reconstruct(&self.mempool.lock().unwrap());
+        let missing = partial.missing();
+        if missing.is_empty() {
+            match partial.fill(vec![]) {
+                Some(block) => self.accept_blocks(&[block], peer, delay_list),
+                None => peer.write(Message::GetBlocks(vec![hash])),
+            }
+            return;
+        }
+        debug!("Compact block {} misses {} of {} transactions", hash, missing.len(), compact.short_ids.len());
+        self.partial_blocks.lock().unwrap().insert(hash, peer.addr(), partial);
+        peer.write(Message::GetBlockTransactions(hash, missing));
+    }
+
+    /// Insert the blocks a peer sent us, holding back those whose parent is missing.
+    fn accept_blocks(&self, blocks: &[Block], peer: &peer::Handle, delay_list: &mut Vec<u128>) {
+        // if get blocks message
+        // insert the blocks into blockchain
+        let mut new_blocks = Vec::new();
+        let mut catch_up = false;
+        let mut blc = self.blockchain.lock().unwrap();
+        let mut orphans = self.orphans.lock().unwrap();
+        let mut downloads = self.downloads.lock().unwrap();
+        let mut mempool = self.mempool.lock().unwrap();
+        for block in blocks{
+            let hash = block.hash();
+            downloads.received(&hash);
+            peer.mark_known(&[hash]);
+            // if the block exisits in the blockchain or waits for its parent
+            if blc.blocks.contains_key(&hash) || orphans.contains(&hash){
+                continue;
+            }
+            // first time receive this block,calculate the delay
+            let recevie_time = now();
+            let b_tsp = block.header.timestamp.clone();
+            let block_delay = recevie_time.saturating_sub(b_tsp);
+            info!("block delay: {} (w)", &block_delay);
+            // compute the average delay
+            delay_list.push(block_delay);
+            let average_delay : u128 = delay_list.iter().sum();
+            let average_delay = average_delay / (delay_list.len() as u128);
+            info!("Average block delay:{}(w)", &average_delay);
+
+            // Checks
+            // 1. limits check 2. pow and parent check 3. orphan block handler
+            // blocks over the limits of the chain params are invalid
+            if block.data.len() > blc.params.max_block_txs
+                || block.size() > blc.params.max_block_bytes {
+                warn!("Ignoring block {} over the block limits", hash);
+                self.server.report(peer, Misbehaviour::InvalidBlock);
+                continue;
+            }
+            // the header may be valid while the transactions under it were swapped
+            if merkle_root(&block.data) != block.header.merkle_root {
+                warn!("Ignoring block {} whose transactions do not match its header", hash);
+                self.server.report(peer, Misbehaviour::InvalidBlock);
+                continue;
+            }
+            match blc.insert_header(&block.header){
+                Ok(_) => {}
+                Err(HeaderError::InvalidProofOfWork(_)) => {
+                    // if pow doesn't match, just ignore this new block
+                    self.server.report(peer, Misbehaviour::InvalidProofOfWork);
+                    continue;
+                }
+                Err(HeaderError::UnknownParent(_)) => {
+                    // we are missing part of the chain, ask for the headers leading here
+                    orphans.insert(block.clone(), peer);
+                    catch_up = true;
+                    continue;
+                }
+            }
+            if !blc.blocks.contains_key(&block.header.parent){
+                // the header connects, the parent block has been asked for already
+                orphans.insert(block.clone(), peer);
+                continue;
+            }
+            // if blocks have parents then apply them like our own
+            if !connect_block(block, &mut blc, &mut mempool) {
+                self.server.report(peer, Misbehaviour::InvalidBlock);
+                continue;
+            }
+            new_blocks.push(hash);
+            // after inserting a new block, we need to look through
+            // if the new block is some orphan's parents
+            let mut parents = vec![hash];
+            while let Some(parent) = parents.pop(){
+                for (child, sender) in orphans.take_children(&parent){
+                    if blc.insert_header(&child.header).is_err(){
+                        // whoever sent the orphan is to blame, not the sender of its parent
+                        self.server.report(&sender, Misbehaviour::InvalidProofOfWork);
+                        continue;
+                    }
+                    if !connect_block(&child, &mut blc, &mut mempool) {
+                        self.server.report(&sender, Misbehaviour::InvalidBlock);
+                        continue;
+                    }
+                    new_blocks.push(child.hash());
+                    parents.push(child.hash());
+                }
+            }
+        }
+        saw_blocks(peer, &blc, blocks.iter().map(Block::hash));
+        let locator = blc.locator();
+        if !new_blocks.is_empty(){
+            // count the numbers of block in the blockchain
+            let tip = blc.tip();
+            let num_in_blc = blc.heights.get(&tip).expect("failed");
+            info!("We have {} blocks in our blockchain(w)", &num_in_blc);
+        }
+        drop(mempool);
+        drop(downloads);
+        drop(orphans);
+        drop(blc);
+        if catch_up{
+            peer.write(Message::GetHeaders(locator));
+        }
+        if !new_blocks.is_empty(){
+            // the miner has to move to the new tip
+            self.miner.new_tip();
+            // need to announce after inserted a new block, except to whoever has it
+            self.server.announce(Inventory::Blocks(new_blocks));
+            // keep the download going
+            self.request_blocks(Some(peer));
+        }
     }
 
     fn worker_loop(&self) {
-        // create hashmap to store the parent-losting block
-        let mut buffer_hash = HashMap::new();
-        let mut buffer_block = HashMap::new();
         let mut delay_list = Vec::new();
         loop {
             let msg = self.msg_chan.recv().unwrap();
             let (msg, peer) = msg;
             // let mut blc = self.blockchain.lock().unwrap();
             // let mut mp = self.mempool.lock().unwrap();
-            let msg: Message = bincode::deserialize(&msg).unwrap();
+            let msg: Message = match msg.message() {
+                Ok(msg) => msg,
+                Err(e) => {
+                    // a frame whose payload does not match its type breaks the framing too
+                    warn!("Undecodable message from peer {}: {}", peer.addr(), e);
+                    self.server.report(&peer, Misbehaviour::MalformedMessage);
+                    self.server.disconnect(&peer);
+                    continue;
+                }
+            };
             match msg {
                 Message::Ping(nonce) => {
                     // if receive the Ping message
@@ -69,6 +467,29 @@ impl Context {
                     peer.write(Message::Pong(nonce.to_string()));
                 }
 
+                Message::Version(_) | Message::Verack => {
+                    // the server completes the handshake before handing messages over
+                    debug!("Ignoring handshake message after the handshake");
+                }
+
+                Message::GetAddr => {
+                    let addrs = self.addrbook.lock().unwrap().sample(MAX_ADDR_PER_MESSAGE);
+                    peer.write(Message::Addr(addrs));
+                }
+
+                Message::Addr(addrs) => {
+                    if addrs.len() > MAX_ADDR_PER_MESSAGE {
+                        warn!("Ignoring oversized addr message with {} entries", addrs.len());
+                        self.server.report(&peer, Misbehaviour::OversizedMessage);
+                        continue;
+                    }
+                    let mut addrbook = self.addrbook.lock().unwrap();
+                    for known in &addrs {
+                        addrbook.add(known.addr, known.last_seen);
+                    }
+                    debug!("Learned {} addresses, {} known", addrs.len(), addrbook.len());
+                }
+
                 Message::Pong(nonce) => {
                     // print the Pong message
                     debug!("Pong: {}", nonce);
@@ -76,156 +497,177 @@ impl Context {
                 }
 
                 Message::NewBLockHashes(newblockhashes) => {
+                    peer.mark_known(&newblockhashes);
                     // if get newblockhashes message and the hashes not in the blockchain
                     // return getblocks message
                     let mut lost_block = Vec::new();
-                    let mut blc = self.blockchain.lock().unwrap();
+                    let blc = self.blockchain.lock().unwrap();
+                    // near the tip the transactions are usually in our mempool already
+                    let synced = blc.best_header() == blc.tip();
+                    saw_blocks(&peer, &blc, newblockhashes.iter().copied());
+                    let orphans = self.orphans.lock().unwrap();
                     // info!("get blockhashes!(w)");
                     for hash in &newblockhashes{
-                        if !blc.blocks.contains_key(&hash){
+                        if !blc.blocks.contains_key(&hash) && !orphans.contains(hash){
                             lost_block.push(hash.clone()); 
                         }
                     }
+                    drop(orphans);
                     drop(blc);
-                    peer.write(Message::GetBlocks(lost_block));
+                    // only ask for what nobody has been asked for yet
+                    let lost_block = self.downloads.lock().unwrap().start(peer.addr(), &lost_block, Instant::now());
+                    if !lost_block.is_empty(){
+                        if synced{
+                            peer.write(Message::GetCompactBlocks(lost_block));
+                        }
+                        else{
+                            peer.write(Message::GetBlocks(lost_block));
+                        }
+                    }
                 }
 
                 Message::GetBlocks(blockhashes) => {
                     // if get getblocks message and the hashes in ur blockchain
                     // return blocks message
                     let mut exisited_hashes = Vec::new();
-                    let mut blc = self.blockchain.lock().unwrap();
+                    let blc = self.blockchain.lock().unwrap();
                     // info!("get getblocks mess!");
+                    // the reply has to fit in one frame, the rest is asked for again later
+                    let mut reply_size = 0;
                     for hash in &blockhashes{
-                        if blc.blocks.contains_key(&hash){
-                            let block_info = blc.blocks.get(&hash).expect("failed");
+                        if let Some(block_info) = blc.public_block(&hash) {
+                            reply_size += block_info.size();
+                            if reply_size > blc.params.max_message_bytes - MESSAGE_OVERHEAD {
+                                break;
+                            }
                             exisited_hashes.push(block_info.clone());
                         }
                     }
                     drop(blc);
+                    peer.mark_known(&exisited_hashes.iter().map(|b| b.hash()).collect::<Vec<_>>());
                     peer.write(Message::Blocks(exisited_hashes));
                 }
 
                 Message::Blocks(blocks) => {
-                    // if get blocks message
-                    // insert the blocks into blockchain
-                    let mut new_blocks = Vec::new();
-                    let mut lost_block = Vec::new();
-                    for block in &blocks{
-                        let mut blc = self.blockchain.lock().unwrap();
-                        let hash = &block.hash();
-                        if !blc.blocks.contains_key(hash){// if the block doesn't exisit in the blockchain
-                            // Checks
-                            // 1. pow check 2. parent check 3. orphan block handler
-                            // PoW check
-                            if !buffer_block.contains_key(hash){
-                                // first time receive this block,calculate the delay
-                                let recevie_time = now();
-                                let b_tsp = block.header.timestamp.clone();
-                                let block_delay = recevie_time - b_tsp;
-                                info!("block delay: {} (w)", &block_delay);
-                                // compute the average delay
-                                delay_list.push(block_delay);
-                                let average_delay : u128 = delay_list.iter().sum();
-                                let average_delay = average_delay / (delay_list.len() as u128);
-                                info!("Average block delay:{}(w)", &average_delay);
+                    self.accept_blocks(&blocks, &peer, &mut delay_list);
+                }
 
+                Message::GetCompactBlocks(blockhashes) => {
+                    let blc = self.blockchain.lock().unwrap();
+                    let compacts: Vec<CompactBlock> = blockhashes
+                        .iter()
+                        .filter_map(|hash| blc.public_block(hash))
+                        .map(CompactBlock::new)
+                        .collect();
+                    drop(blc);
+                    for compact in compacts {
+                        peer.mark_known(&[compact.header.hash()]);
+                        peer.write(Message::CompactBlock(compact));
+                    }
+                }
+
+                Message::CompactBlock(compact) => {
+                    self.accept_compact_block(compact, &peer, &mut delay_list);
+                }
+
+                Message::GetBlockTransactions(hash, indexes) => {
+                    let blc = self.blockchain.lock().unwrap();
+                    let txs: Option<Vec<SignedTransaction>> = blc.public_block(&hash).map(|block| {
+                        indexes.iter().filter_map(|i| block.data.get(*i as usize).cloned()).collect()
+                    });
+                    drop(blc);
+                    match txs {
+                        Some(txs) => peer.write(Message::BlockTransactions(hash, txs)),
+                        None => debug!("Peer {} asked for transactions of unknown block {}", peer.addr(), hash),
+                    }
+                }
+
+                Message::BlockTransactions(hash, txs) => {
+                    let partial = self.partial_blocks.lock().unwrap().take(hash, peer.addr());
+                    match partial.map(|partial| partial.fill(txs)) {
+                        Some(Some(block)) => self.accept_blocks(&[block], &peer, &mut delay_list),
+                        Some(None) => {
+                            // wrong transactions or a short ID collision, get the whole block
+                            warn!("Could not rebuild compact block {}, fetching it in full", hash);
+                            peer.write(Message::GetBlocks(vec![hash]));
+                        }
+                        None => debug!("Ignoring unrequested transactions of block {} from {}", hash, peer.addr()),
+                    }
+                }
+
+                Message::GetHeaders(locator) => {
+                    let blc = self.blockchain.lock().unwrap();
+                    let headers = blc.headers_after(&locator, MAX_HEADERS);
+                    drop(blc);
+                    peer.write(Message::Headers(headers));
+                }
+
+                Message::Headers(headers) => {
+                    if headers.len() > MAX_HEADERS {
+                        warn!("Ignoring oversized headers message with {} entries", headers.len());
+                        self.server.report(&peer, Misbehaviour::OversizedMessage);
+                        continue;
+                    }
+                    // validate the header chain first, then fetch the blocks of the new headers
+                    let mut new_headers = 0;
+                    let mut blc = self.blockchain.lock().unwrap();
+                    for header in &headers {
+                        match blc.insert_header(header) {
+                            Ok(true) => new_headers += 1,
+                            Ok(false) => {}
+                            Err(HeaderError::InvalidProofOfWork(_)) => {
+                                self.server.report(&peer, Misbehaviour::InvalidProofOfWork);
+                                break;
                             }
-                            let now_block = block.clone();
-                            let difficulty = block.header.difficulty.clone();
-                            if hash > &difficulty{
-                                // if pow doesn't match, just ignore this new block
-                                continue;
-                            }
-                            // use the parent's hash to find parent's height
-                            let parent_hash = block.header.parent.clone();
-                            // if the parent has not inserted, we cannot find the height in the hash map
-                            // need to discord the panic situation
-                            // Parent check
-                            let parent_result = blc.heights.get(&parent_hash);
-                            let mut now_height = 0;
-                            let mut flag = 0;
-                            match parent_result{
-                                None => flag = 1,
-                                Some(v) => now_height = v + 1,
-                            }
-                            if flag == 1{
-                                // key:parent_hash value:child_hash
-                                buffer_hash.insert(parent_hash.clone(), hash.clone());
-                                // key:child_hash value:child_block
-                                buffer_block.insert(hash.clone(), now_block.clone());
-                                // lost_block store what we have hash but no blocks
-                                lost_block.push(parent_hash.clone());
-                                // send a get block message
-                                peer.write(Message::GetBlocks(lost_block.clone()));
-                                continue;
-                            }
-                            let parent_block = blc.blocks.get(&parent_hash).expect("failed");
-                            let parent_difficulty = parent_block.header.difficulty.clone();
-                            if difficulty != parent_difficulty{
-                                // if difficulty doesn't match, just ignore this new block
-                                continue;
-                            }
-                            // if blocks have parents then insert block and height
-                            blc.heights.insert(hash.clone(), now_height);
-                            blc.blocks.insert(hash.clone(), now_block);
-                            new_blocks.push(hash.clone());
-                            // after inserting a new block, we need to look through
-                            // if the new block is some orphan's parents
-                            // turn into iteratively
-                            let mut p_hash = hash.clone();
-                            while buffer_hash.contains_key(&p_hash){
-                                let child_hash = buffer_hash.get(&p_hash).expect("failed").clone();
-                                let child_block = buffer_block.get(&child_hash).expect("failed");
-                                let new_p_height = blc.heights.get(&p_hash).expect("failed");
-                                let child_height = new_p_height + 1;
-                                blc.blocks.insert(child_hash.clone(), child_block.clone());
-                                blc.heights.insert(child_hash.clone(), child_height);
-                                // after inserting the lost hash in buffer
-                                // need to broadcast it and delete it from the buffer
-                                new_blocks.push(child_hash.clone());
-                                buffer_block.remove(&child_hash);
-                                buffer_hash.remove(&p_hash);
-                                p_hash = child_hash.clone()
+                            Err(HeaderError::UnknownParent(parent)) => {
+                                debug!("Headers from peer {} don't connect at {}", peer.addr(), parent);
+                                break;
                             }
-                            // need to broadcast after inserted a new block
-                            self.server.broadcast(Message::NewBLockHashes(new_blocks.clone()));
-                            peer.write(Message::NewBLockHashes(new_blocks.clone()));
-                            // count the numbers of block in the blockchain
-                            let tip = blc.tip();
-                            let num_in_blc = blc.heights.get(&tip).expect("failed");
-                            info!("We have {} blocks in our blockchain(w)", &num_in_blc);
-                            
                         }
-                        drop(blc);
                     }
-                    
+                    saw_blocks(&peer, &blc, headers.iter().map(Header::hash));
+                    // a full message means the peer has more
+                    let locator = blc.locator();
+                    let best_height = blc.headers[&blc.best_header()].1;
+                    drop(blc);
+                    if headers.len() == MAX_HEADERS {
+                        peer.write(Message::GetHeaders(locator));
+                    }
+                    if new_headers > 0 {
+                        info!("Got {} new headers, best header height {}", new_headers, best_height);
+                    }
+                    self.request_blocks(Some(&peer));
                 }
 
                 Message::NewTransactionHashes(newtxhashes) => {
+                    peer.mark_known(&newtxhashes);
                     let mut mp = self.mempool.lock().unwrap();
                     let mut lost_tx = Vec::new();
                     for hash in &newtxhashes{
-                        if !mp.valid_tx.contains_key(&hash){
+                        if !mp.contains(hash){
                             lost_tx.push(hash.clone());
                         }
                     }
-                    peer.write(Message::GetTransactions(lost_tx));
                     drop(mp);
+                    if !lost_tx.is_empty(){
+                        peer.write(Message::GetTransactions(lost_tx));
+                    }
                     // info!("new transaction hashes received(w)");//test
                 }
 
                 Message::GetTransactions(txhashes) => {
                     let mut exisited_hashes = Vec::new();
                     let mut mp = self.mempool.lock().unwrap();
+                    let mut sent = Vec::new();
                     for hash in &txhashes{
-                        if mp.valid_tx.contains_key(&hash){
-                            let tx_info = mp.valid_tx.get(&hash).expect("failed");
+                        if let Some(tx_info) = mp.get(hash){
                             exisited_hashes.push(tx_info.clone());
+                            sent.push(*hash);
                         }
                     }
-                    peer.write(Message::Transactions(exisited_hashes));
                     drop(mp);
+                    peer.mark_known(&sent);
+                    peer.write(Message::Transactions(exisited_hashes));
                     // info!("To get tx(w)");
                 }
 
@@ -233,40 +675,38 @@ impl Context {
                     // mempool operations!
                     info!("got new tx!(w)");
                     // if get transactions, do checks
+                    let mut accepted = Vec::new();
                     for signedtx in &signedtransactions{
-                        let transaction = &signedtx.tx;
-                        let sig = &signedtx.signature;
-                        let public_key = &signedtx.public_key;
-                        // 1.check if signature is signed correctly by the public key
-                        if !verify(transaction, public_key.clone(), sig.clone()){
-                            continue;
-                        }
-
-                        let mut blc = self.blockchain.lock().unwrap();
-                        let state = &blc.state;
-                        let mut public_bytes = [0; 20];
-                        public_bytes.copy_from_slice(&public_key);
-                        //  check if the pbkey match the owners address
-                        if !state.contains_key(&public_bytes.into()){
-                            continue;
-                        }
-
-                        // 2.double spending check
-                        let spend_value = transaction.value;
-                        let an = transaction.account_nonce;
-                        let (an_state,b) = state.get(&public_bytes.into()).expect("failed");
-                        if spend_value > b.clone() || an != an_state + 1{
+                        peer.mark_known(&[signedtx.txid()]);
+                        // a bad signature is the sender's fault, a stale balance or nonce may not be
+                        if !verify(&signedtx.tx, signedtx.public_key.clone(), signedtx.signature.clone()){
+                            self.server.report(&peer, Misbehaviour::InvalidSignature);
                             continue;
                         }
+                        // signature, owner and balance checks
+                        let blc = self.blockchain.lock().unwrap();
+                        let an_state = match check_against_state(signedtx, blc.state()){
+                            Some(an_state) => an_state,
+                            None => continue,
+                        };
                         drop(blc);
                         let mut mp = self.mempool.lock().unwrap();
-                        if !mp.valid_tx.contains_key(&transaction.hash()){
-                            mp.valid_tx.insert(transaction.hash().clone(),signedtx.clone());
-                            info!("new tx in pool!");
+                        // a higher fee transaction may replace a pending one with the same nonce
+                        match mp.insert(signedtx.clone(), an_state){
+                            Ok(hash) => {
+                                info!("new tx in pool!");
+                                accepted.push(hash);
+                            }
+                            Err(e) => debug!("tx rejected by mempool: {:?}", e),
                         }
                         drop(mp);
                         // 3.when get blocks, check transactions again(send message to get transactions info)
                     }
+                    // relay new transactions and replacements to our peers
+                    if !accepted.is_empty(){
+                        self.miner.new_transactions();
+                        self.server.announce(Inventory::Transactions(accepted));
+                    }
                 }
                 
             }
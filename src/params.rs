@@ -0,0 +1,59 @@
+use crate::crypto::hash::H256;
+
+/// Consensus parameters of the network a node runs on
+#[derive(Clone, Debug)]
+pub struct ChainParams {
+    pub name: &'static str,
+    /// First bytes of every P2P frame on this network, unlike those of Bitcoin so neither network
+    /// takes the nodes of the other for its own
+    pub magic: [u8; 4],
+    /// Target of the genesis block, every block has to meet the target of its parent
+    pub difficulty: H256,
+    /// Number of accounts funded in the genesis state, their keys come from `key_pair::ico`
+    pub ico_accounts: u8,
+    /// Balance of each of those accounts
+    pub ico_balance: u32,
+    /// Coins credited to the coinbase address of every block
+    pub block_reward: u32,
+    /// Maximum number of transactions in a block
+    pub max_block_txs: usize,
+    /// Maximum serialized size of a block, in bytes
+    pub max_block_bytes: usize,
+    /// Maximum payload of a P2P message, in bytes
+    pub max_message_bytes: usize,
+}
+
+impl ChainParams {
+    /// The default network
+    pub fn main() -> Self {
+        ChainParams {
+            name: "main",
+            magic: [0xc4, 0x7a, 0x91, 0x3e],
+            // adjust the mining rate in ubuntu
+            difficulty: [0, 1, 14, 4, 3, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+                0, 0, 0, 0, 0, 0, 0].into(),
+            ico_accounts: 4,
+            ico_balance: 1000,
+            block_reward: 0,
+            max_block_txs: 2,
+            max_block_bytes: 1 << 16,
+            max_message_bytes: 1 << 23,
+        }
+    }
+
+    /// Regression testing: every hash meets the target, so blocks are mined on demand. The genesis
+    /// accounts are derived, not random, so nodes agree on the genesis state.
+    pub fn regtest() -> Self {
+        ChainParams {
+            name: "regtest",
+            magic: [0xc4, 0x7a, 0x91, 0x52],
+            difficulty: [0xff; 32].into(),
+            ico_accounts: 4,
+            ico_balance: 1000,
+            block_reward: 50,
+            max_block_txs: 1000,
+            max_block_bytes: 1 << 20,
+            max_message_bytes: 1 << 25,
+        }
+    }
+}
@@ -2,15 +2,17 @@ use serde::{Serialize,Deserialize};
 use ring::signature::{self,Ed25519KeyPair, Signature, KeyPair, VerificationAlgorithm, EdDSAParameters};
 use rand::{Rng};
 use crate::crypto::hash::{H160,H256};
-use std::collections::HashMap;
 use crate::network::server::Handle as ServerHandle;
-use crate::network::message::Message;
-use log::info;
+use crate::miner::Handle as MinerHandle;
+use crate::network::inventory::Inventory;
+use log::{info, warn};
 use crossbeam::channel::{unbounded, Receiver, Sender, TryRecvError};
+use std::collections::HashMap;
 use std::time;
 use std::thread;
 use std::sync::{Arc, Mutex};
 use crate::blockchain::*;
+use crate::mempool::Mempool;
 use crate::block::*;
 use crate::crypto::merkle::*;
 use crate::crypto::hash::Hashable;
@@ -25,6 +27,8 @@ pub struct Transaction {
     pub recipient_address:H160,
     pub value:u32, 
     pub account_nonce:u8,
+    // paid by the sender on top of the value, decides the priority in the mempool
+    pub fee:u32,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -37,13 +41,19 @@ pub struct SignedTransaction {
 
 
 impl Transaction{
-    pub fn new (recipient_address:H160, value:u32,account_nonce:u8) -> Self{
+    pub fn new (recipient_address:H160, value:u32,account_nonce:u8, fee:u32) -> Self{
         Transaction{
             recipient_address,
             value,
             account_nonce,
+            fee,
         }
     }
+
+    /// Total amount withdrawn from the sender's balance, `None` if it does not fit in a `u32`
+    pub fn cost(&self) -> Option<u32>{
+        self.value.checked_add(self.fee)
+    }
 }
 
 impl SignedTransaction{
@@ -54,36 +64,32 @@ impl SignedTransaction{
             public_key,
         }
     }
-}
 
-impl Hashable for Transaction{
-    fn hash(&self) -> H256 {
-        let transaction_bytes = bincode::serialize(self).unwrap();
-        let result = ring::digest::digest(&ring::digest::SHA256, &transaction_bytes);
-        let transaction_hash = result.into();
-        return transaction_hash;
+    /// Address of the account that signed the transaction
+    pub fn sender(&self) -> H160 {
+        public_key_to_address(&self.public_key).into()
     }
 }
 
-impl Hashable for SignedTransaction{
-    fn hash(&self) -> H256 {
-        let transaction_bytes = bincode::serialize(self).unwrap();
-        let result = ring::digest::digest(&ring::digest::SHA256, &transaction_bytes);
-        let transaction_hash = result.into();
-        return transaction_hash;
+impl SignedTransaction{
+    /// The transaction id: commits to the transaction and the sender's public key, but not to the
+    /// signature. This is the id used for mempool keys, relay messages, short ids and the API.
+    pub fn txid(&self) -> H256 {
+        let transaction_bytes = bincode::serialize(&(&self.tx, &self.public_key)).unwrap();
+        ring::digest::digest(&ring::digest::SHA256, &transaction_bytes).into()
     }
-}
 
-pub struct Mempool {
-    pub valid_tx: HashMap<H256,SignedTransaction>,
+    /// Hash of the whole signed transaction, including the signature. Used for the Merkle leaves.
+    pub fn wtxid(&self) -> H256 {
+        let transaction_bytes = bincode::serialize(self).unwrap();
+        ring::digest::digest(&ring::digest::SHA256, &transaction_bytes).into()
+    }
 }
 
-impl Mempool{
-    pub fn new() -> Self{
-        let newhashmap = HashMap::new();
-        Mempool{
-            valid_tx:newhashmap,
-        }
+impl Hashable for SignedTransaction{
+    /// Same as `txid`
+    fn hash(&self) -> H256 {
+        self.txid()
     }
 }
 
@@ -99,8 +105,13 @@ pub fn sign(t: &Transaction, key: &Ed25519KeyPair) -> Signature {
 }
 
 pub fn conversion(public_key: &<Ed25519KeyPair as KeyPair>::PublicKey) -> [u8;20]{
+    public_key_to_address(public_key.as_ref())
+}
+
+/// Hash the raw public key bytes and take the last 20 bytes as the address
+pub fn public_key_to_address(public_key: &[u8]) -> [u8;20]{
     // hash the public key
-    let address = ring::digest::digest(&ring::digest::SHA256, &public_key.as_ref());
+    let address = ring::digest::digest(&ring::digest::SHA256, public_key);
     let mut raw_hash: [u8; 20] = [0; 20];
     let a = address.as_ref();
     let num = a.len();
@@ -140,23 +151,44 @@ pub fn verify(t: &Transaction, public_key: Vec<u8>, signature: Vec<u8>) -> bool
     
 }
 
+/// Check a transaction against the ledger state before it enters the mempool:
+/// the signature, the sender account and its balance.
+/// Returns the sender's current account nonce, which the mempool needs to order it.
+pub fn check_against_state(signedtx: &SignedTransaction, state: &HashMap<H160, (u8, u32)>) -> Option<u8> {
+    // 1.check if signature is signed correctly by the public key
+    if !verify(&signedtx.tx, signedtx.public_key.clone(), signedtx.signature.clone()){
+        return None;
+    }
+    //  check if the pbkey match the owners address
+    let (an_state, b) = state.get(&signedtx.sender())?;
+    // 2.double spending check
+    // nonces ahead of the state are kept in the mempool until the gap is filled
+    if signedtx.tx.cost()? > *b{
+        return None;
+    }
+    Some(*an_state)
+}
+
 // transaction generator
 pub struct Context {
     /// Channel for receiving control signal
     server: ServerHandle,
     blockchain: Arc<Mutex<Blockchain>>,
     mempool: Arc<Mutex<Mempool>>,
+    miner: MinerHandle,
 }
 
 pub fn new(
     server: &ServerHandle,
     blockchain: &Arc<Mutex<Blockchain>>,
     mempool: &Arc<Mutex<Mempool>>,
+    miner: &MinerHandle,
 ) -> Context {
     Context {
         server: server.clone(),
         blockchain: Arc::clone(blockchain),
-        mempool: Arc::clone(mempool)
+        mempool: Arc::clone(mempool),
+        miner: miner.clone(),
     }
 }
 
@@ -172,22 +204,12 @@ impl Context {
     }
 
     fn tx_loop(&mut self) {
-        // connect to server to broadcast
-        let mut newtxhashes = Vec::new();
-        let mut account = Vec::new();
-
-        // initiate diffrent key pair for different node
-        for user in 1..3{
-            let u = key_pair::random();
-            let public_key = u.public_key();
-            let account_address = conversion(public_key).into();
-            let balance = 50;
-            let account_nonce = user;
-            account.push(u);
-            let mut blc = self.blockchain.lock().unwrap();
-            // insert the state into blockchain
-            blc.state.insert(account_address, (account_nonce, balance));
-            drop(blc);
+        // spend from the genesis accounts, every node knows their keys
+        let ico_accounts = self.blockchain.lock().unwrap().params.ico_accounts;
+        let account: Vec<Ed25519KeyPair> = (0..ico_accounts).map(key_pair::ico).collect();
+        if account.is_empty() {
+            warn!("no genesis account to generate transactions from");
+            return;
         }
 
         loop{
@@ -207,8 +229,8 @@ impl Context {
             let blc = self.blockchain.lock().unwrap();
             let mut num = 0;
             let mut recipient_address:H160 = [0;20].into();
-            let target_receive = rng.gen_range(0, &blc.state.len());
-            for (receiver,x) in &blc.state{
+            let target_receive = rng.gen_range(0, blc.state().len());
+            for (receiver,x) in blc.state(){
                 if num == target_receive{
                     recipient_address = receiver.clone();
                     break;
@@ -221,11 +243,12 @@ impl Context {
             let account_num = account.len();
             let chosen_send = rng.gen_range(0, account_num);
             let send = conversion(&account[chosen_send].public_key()).into();
-            let (an,b) = blc.state.get(&send).expect("failed");
+            let (an,b) = blc.state().get(&send).expect("failed");
             
             
             // choose send value
             let mut value = 1;
+            let fee = rng.gen_range(1, 6);
             // if b > &0 {
             //     value = rng.gen_range(1, b);
             // }
@@ -233,25 +256,38 @@ impl Context {
             //     continue;
             // }
 
-            // set nonce
-            let account_nonce = an + 1;
+            // set nonce, following the transactions of this account still waiting in the mempool
+            let an = *an;
             drop(blc);
+            let mut mp = self.mempool.lock().unwrap();
+            let account_nonce = match mp.next_nonce(&send, an) {
+                Some(nonce) => nonce,
+                None => {
+                    drop(mp);
+                    warn!("account {} has used up its nonces", send);
+                    thread::sleep(time::Duration::from_secs(10));
+                    continue;
+                }
+            };
 
-            let new_tx = Transaction::new(recipient_address, value, account_nonce.into());
+            let new_tx = Transaction::new(recipient_address, value, account_nonce, fee);
             let signature = sign(&new_tx, &account[chosen_send]).as_ref().to_vec();
-            let signed_tx = SignedTransaction::new(new_tx, signature, conversion(account[chosen_send].public_key()).to_vec());
-            let newtxhash = signed_tx.hash();
-            newtxhashes.push(newtxhash);
-            
-            //get the lock
-            let mut mp = self.mempool.lock().unwrap();
-            // save in memepool
-            mp.valid_tx.insert(newtxhash.clone(),signed_tx.clone());
-            drop(mp);
+            let signed_tx = SignedTransaction::new(new_tx, signature, account[chosen_send].public_key().as_ref().to_vec());
 
-            // we need to broadcast to worker first,then worker check and add it into mempool
-            self.server.broadcast(Message::NewTransactionHashes(newtxhashes.clone()));
-            info!("new transaction occured!");//test
+            // save in memepool
+            match mp.insert(signed_tx, an) {
+                Ok(newtxhash) => {
+                    drop(mp);
+                    self.miner.new_transactions();
+                    // the worker of each peer checks it and adds it into its mempool
+                    self.server.announce(Inventory::Transactions(vec![newtxhash]));
+                    info!("new transaction occured!");//test
+                }
+                Err(e) => {
+                    drop(mp);
+                    warn!("generated transaction rejected by mempool: {:?}", e);
+                }
+            }
             
             // thread sleep
             let interval = time::Duration::from_micros(10000000 as u64);
@@ -283,4 +319,16 @@ mod tests {
     //     let signature = sign(&t, &key);
     //     assert!(verify(&t, &(key.public_key()), &signature));
     // }
+
+    #[test]
+    fn overflowing_cost_is_rejected() {
+        let key = key_pair::random();
+        let tx = Transaction::new([0; 20].into(), u32::MAX, 1, 1);
+        assert_eq!(tx.cost(), None);
+        let signature = sign(&tx, &key).as_ref().to_vec();
+        let signed = SignedTransaction::new(tx, signature, key.public_key().as_ref().to_vec());
+        let mut state = HashMap::new();
+        state.insert(signed.sender(), (0, u32::MAX));
+        assert_eq!(check_against_state(&signed, &state), None);
+    }
 } 